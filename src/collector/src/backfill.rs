@@ -0,0 +1,387 @@
+use std::{fmt, str::FromStr, sync::Arc};
+
+use anyhow::Context;
+use db::{DbClient, entities};
+use msg_broker::{PostgresTransport, Priority, Publisher, SendOptions};
+use solana_client::{
+    nonblocking::rpc_client::RpcClient, rpc_client::GetConfirmedSignaturesForAddress2Config,
+    rpc_response::RpcConfirmedTransactionStatusWithSignature,
+};
+use solana_commitment_config::CommitmentConfig;
+use solana_pubkey::Pubkey;
+use solana_signature::Signature;
+use uuid::Uuid;
+
+use crate::{AppConfig, Msg, PROGRAMS, SLOT_HEADER, handlers::Raydium};
+
+/// Signatures fetched per `getSignaturesForAddress` call, the most it allows.
+const PAGE_SIZE: usize = 1000;
+
+/// Signatures gone through between saves of the progress within a page, a
+/// crash republishes at most this many.
+const SAVE_EVERY: usize = 100;
+
+pub const BACKFILL_USAGE: &str = "usage: collector backfill <from> [to] [--program <id>]
+bounds are sig:<signature>, slot:<slot> or time:<unix seconds>, signatures are exclusive";
+
+/// One end of a backfill range.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Bound {
+    Signature(String),
+    Slot(u64),
+    /// Unix seconds, compared against the block time.
+    Time(i64),
+}
+
+impl FromStr for Bound {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, value) = s
+            .split_once(':')
+            .with_context(|| format!("expected sig:, slot: or time: in {}", s))?;
+        Ok(match kind {
+            "sig" => Bound::Signature(value.to_string()),
+            "slot" => Bound::Slot(
+                value
+                    .parse()
+                    .with_context(|| format!("invalid slot {}", value))?,
+            ),
+            "time" => Bound::Time(
+                value
+                    .parse()
+                    .with_context(|| format!("invalid unix time {}", value))?,
+            ),
+            _ => anyhow::bail!("unknown bound {}, expected sig, slot or time", kind),
+        })
+    }
+}
+
+impl fmt::Display for Bound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Bound::Signature(signature) => write!(f, "sig:{}", signature),
+            Bound::Slot(slot) => write!(f, "slot:{}", slot),
+            Bound::Time(time) => write!(f, "time:{}", time),
+        }
+    }
+}
+
+/// Whether `status` is older than `bound`. Signature bounds are left to the
+/// RPC node.
+fn older_than(status: &RpcConfirmedTransactionStatusWithSignature, bound: &Bound) -> bool {
+    match bound {
+        Bound::Signature(_) => false,
+        Bound::Slot(slot) => status.slot < *slot,
+        Bound::Time(time) => status
+            .block_time
+            .is_some_and(|block_time| block_time < *time),
+    }
+}
+
+fn newer_than(status: &RpcConfirmedTransactionStatusWithSignature, bound: &Bound) -> bool {
+    match bound {
+        Bound::Signature(_) => false,
+        Bound::Slot(slot) => status.slot > *slot,
+        Bound::Time(time) => status
+            .block_time
+            .is_some_and(|block_time| block_time > *time),
+    }
+}
+
+/// A range of a program's history to publish, see [`run_backfill`].
+#[derive(Debug, Clone)]
+pub struct Backfill {
+    pub program_id: String,
+    /// The oldest end of the range.
+    pub from: Bound,
+    /// The newest end of the range, the latest confirmed signature if unset.
+    pub to: Option<Bound>,
+}
+
+impl Backfill {
+    /// Parses the arguments following `backfill`, see [`BACKFILL_USAGE`].
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> anyhow::Result<Self> {
+        let mut program_id = Raydium::PROGRAM_ID.to_string();
+        let mut bounds = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--program" => program_id = args.next().context("--program needs an id")?,
+                _ => bounds.push(arg.parse::<Bound>()?),
+            }
+        }
+        let mut bounds = bounds.into_iter();
+        let from = bounds.next().context("missing the start of the range")?;
+        let to = bounds.next();
+        anyhow::ensure!(bounds.next().is_none(), "expected at most two bounds");
+        Ok(Backfill {
+            program_id,
+            from,
+            to,
+        })
+    }
+
+    /// The same for every run of the same range, so a rerun resumes it. An
+    /// open-ended range keeps its id as well, a rerun after it is done starts
+    /// over from the latest signature, see [`run_backfill`].
+    fn id(&self) -> Uuid {
+        let to = self
+            .to
+            .as_ref()
+            .map_or("latest".to_string(), Bound::to_string);
+        let name = format!("{}:{}:{}", self.program_id, self.from, to);
+        Uuid::new_v5(&Uuid::NAMESPACE_OID, name.as_bytes())
+    }
+}
+
+/// Publishes every successful transaction of the program within the range,
+/// newest first, to the program's handler through the broker queue. Messages
+/// go out at [`Priority::Low`] so live swaps are handled first. Progress is
+/// stored every [`SAVE_EVERY`] signatures, running the same backfill again
+/// resumes where it stopped. Once done, the same range is skipped unless it
+/// is open-ended, which is run again up to the now latest signature. Slot and
+/// time bounds page through everything newer than the range once, since
+/// `getSignaturesForAddress` can only start at a signature.
+pub async fn run_backfill(config: AppConfig, backfill: Backfill) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        PROGRAMS.contains(&backfill.program_id.as_str()),
+        "no handler for program {}",
+        backfill.program_id
+    );
+    let address = Pubkey::from_str(&backfill.program_id).context("invalid program id")?;
+    let until = match &backfill.from {
+        Bound::Signature(signature) => {
+            Some(Signature::from_str(signature).context("invalid start signature")?)
+        }
+        _ => None,
+    };
+    let db_client = DbClient::connect(&config.db_config)
+        .await
+        .context("failed to connect to db")?;
+    let rpc_client = RpcClient::new(config.solana_config.rpc_uri.clone());
+    let transport = PostgresTransport::connect(&config.db_config)
+        .await
+        .context("failed to connect publisher transport")?;
+    let publisher = Publisher::new(Arc::new(transport));
+
+    let backfill_id = backfill.id();
+    let mut progress = db_client
+        .get_backfill(backfill_id)
+        .await
+        .context("failed to load backfill progress")?
+        .unwrap_or_else(|| entities::Backfill {
+            backfill_id,
+            program_id: backfill.program_id.clone(),
+            cursor: None,
+            published: 0,
+            done: false,
+        });
+    if progress.done && backfill.to.is_none() {
+        println!(
+            "backfill {} was done, starting over up to the latest signature",
+            backfill_id
+        );
+        progress.cursor = None;
+        progress.published = 0;
+        progress.done = false;
+    }
+    if progress.done {
+        println!(
+            "backfill {} is already done, {} signature(s) published",
+            backfill_id, progress.published
+        );
+        return Ok(());
+    }
+    let mut before = match (&progress.cursor, &backfill.to) {
+        (Some(cursor), _) => Some(cursor.as_str()),
+        (None, Some(Bound::Signature(signature))) => Some(signature.as_str()),
+        (None, _) => None,
+    }
+    .map(Signature::from_str)
+    .transpose()
+    .context("invalid end signature")?;
+    println!(
+        "backfilling {} from {} to {} as {}",
+        backfill.program_id,
+        backfill.from,
+        backfill
+            .to
+            .as_ref()
+            .map_or("latest".to_string(), Bound::to_string),
+        backfill_id
+    );
+
+    loop {
+        let page = rpc_client
+            .get_signatures_for_address_with_config(
+                &address,
+                GetConfirmedSignaturesForAddress2Config {
+                    before,
+                    until,
+                    limit: Some(PAGE_SIZE),
+                    commitment: Some(CommitmentConfig::confirmed()),
+                },
+            )
+            .await
+            .context("failed to get signatures")?;
+        let Some(oldest) = page.last() else {
+            break;
+        };
+        let cursor = oldest.signature.clone();
+
+        let mut reached_start = page.len() < PAGE_SIZE;
+        for (index, status) in page.iter().enumerate() {
+            if index > 0 && index % SAVE_EVERY == 0 {
+                progress.cursor = Some(page[index - 1].signature.clone());
+                db_client
+                    .upsert_backfill(&progress)
+                    .await
+                    .context("failed to store backfill progress")?;
+            }
+            if older_than(status, &backfill.from) {
+                reached_start = true;
+                break;
+            }
+            let skip = backfill
+                .to
+                .as_ref()
+                .is_some_and(|to| newer_than(status, to));
+            if skip || status.err.is_some() {
+                continue;
+            }
+            let options = SendOptions::new()
+                .priority(Priority::Low)
+                .header(SLOT_HEADER, status.slot.to_string());
+            let msg = Msg {
+                signature: status.signature.clone(),
+            };
+            let res = match backfill.program_id.as_str() {
+                Raydium::PROGRAM_ID => publisher.send_with::<Raydium>(msg, options).await,
+                program_id => anyhow::bail!("no handler for program {}", program_id),
+            };
+            res.with_context(|| format!("failed to publish {}", status.signature))?;
+            progress.published += 1;
+        }
+
+        before = Some(Signature::from_str(&cursor).context("invalid signature from rpc")?);
+        progress.cursor = Some(cursor);
+        if reached_start {
+            break;
+        }
+        db_client
+            .upsert_backfill(&progress)
+            .await
+            .context("failed to store backfill progress")?;
+    }
+
+    progress.done = true;
+    db_client
+        .upsert_backfill(&progress)
+        .await
+        .context("failed to store backfill progress")?;
+    println!(
+        "backfill {} done, {} signature(s) published",
+        backfill_id, progress.published
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use solana_client::rpc_response::RpcConfirmedTransactionStatusWithSignature;
+
+    use crate::{
+        backfill::{Backfill, Bound, newer_than, older_than},
+        handlers::Raydium,
+    };
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn status(slot: u64, block_time: Option<i64>) -> RpcConfirmedTransactionStatusWithSignature {
+        RpcConfirmedTransactionStatusWithSignature {
+            signature: "sig".to_string(),
+            slot,
+            err: None,
+            memo: None,
+            block_time,
+            confirmation_status: None,
+        }
+    }
+
+    #[test]
+    fn test_parse_bounds() {
+        assert_eq!(
+            "sig:abc".parse::<Bound>().unwrap(),
+            Bound::Signature("abc".to_string())
+        );
+        assert_eq!("slot:42".parse::<Bound>().unwrap(), Bound::Slot(42));
+        assert_eq!(
+            "time:1700000000".parse::<Bound>().unwrap(),
+            Bound::Time(1700000000)
+        );
+        for bound in ["42", "slot:abc", "time:", "block:42"] {
+            assert!(bound.parse::<Bound>().is_err(), "{} parsed", bound);
+        }
+        for bound in ["sig:abc", "slot:42", "time:-5"] {
+            assert_eq!(bound.parse::<Bound>().unwrap().to_string(), bound);
+        }
+    }
+
+    #[test]
+    fn test_backfill_from_args() {
+        let backfill = Backfill::from_args(args(&["slot:10", "slot:20"])).unwrap();
+        assert_eq!(backfill.program_id, Raydium::PROGRAM_ID);
+        assert_eq!(backfill.from, Bound::Slot(10));
+        assert_eq!(backfill.to, Some(Bound::Slot(20)));
+
+        let backfill = Backfill::from_args(args(&["--program", "other", "time:5"])).unwrap();
+        assert_eq!(backfill.program_id, "other");
+        assert_eq!(backfill.from, Bound::Time(5));
+        assert_eq!(backfill.to, None);
+
+        assert!(Backfill::from_args(args(&[])).is_err());
+        assert!(Backfill::from_args(args(&["slot:1", "--program"])).is_err());
+        assert!(Backfill::from_args(args(&["slot:1", "slot:2", "slot:3"])).is_err());
+    }
+
+    #[test]
+    fn test_older_and_newer_than() {
+        let slot = Bound::Slot(10);
+        assert!(older_than(&status(9, None), &slot));
+        assert!(!older_than(&status(10, None), &slot));
+        assert!(newer_than(&status(11, None), &slot));
+        assert!(!newer_than(&status(10, None), &slot));
+
+        let time = Bound::Time(100);
+        assert!(older_than(&status(0, Some(99)), &time));
+        assert!(newer_than(&status(0, Some(101)), &time));
+        assert!(!older_than(&status(0, None), &time));
+        assert!(!newer_than(&status(0, None), &time));
+
+        let signature = Bound::Signature("sig".to_string());
+        assert!(!older_than(&status(0, None), &signature));
+        assert!(!newer_than(&status(u64::MAX, None), &signature));
+    }
+
+    #[test]
+    fn test_backfill_id_is_stable() {
+        let backfill = Backfill::from_args(args(&["slot:10", "slot:20"])).unwrap();
+        let rerun = Backfill::from_args(args(&["slot:10", "slot:20"])).unwrap();
+        assert_eq!(backfill.id(), rerun.id());
+        // Stored progress is found by id, so it must not change across releases.
+        assert_eq!(
+            backfill.id().to_string(),
+            "76e90383-b4f4-55c1-a7d9-f27aa6d731eb"
+        );
+
+        let other = Backfill::from_args(args(&["slot:10", "slot:21"])).unwrap();
+        assert_ne!(backfill.id(), other.id());
+        let open = Backfill::from_args(args(&["slot:10"])).unwrap();
+        assert_ne!(backfill.id(), open.id());
+        let program = Backfill::from_args(args(&["--program", "other", "slot:10", "slot:20"]));
+        assert_ne!(backfill.id(), program.unwrap().id());
+    }
+}
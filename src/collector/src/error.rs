@@ -9,6 +9,10 @@ pub enum HandlerError {
     Db(#[from] DbError),
     #[error(transparent)]
     Decode(#[from] DecodeError),
+    #[error("transaction not found")]
+    NotFound,
+    #[error(transparent)]
+    Publish(#[from] msg_broker::PublishError),
     #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
@@ -22,6 +26,8 @@ impl From<HandlerError> for msg_broker::HandlerError<HandlerError> {
                 DbError::ConcurrentUpdate => msg_broker::HandlerError::transient(value),
                 DbError::Unknown(_) => msg_broker::HandlerError::fatal(value),
             },
+            HandlerError::NotFound => msg_broker::HandlerError::fatal(value),
+            HandlerError::Publish(_) => msg_broker::HandlerError::transient(value),
             HandlerError::Other(_) => msg_broker::HandlerError::fatal(value),
             HandlerError::SolanaRpc(ref error) => match &error.kind {
                 solana_client::client_error::ClientErrorKind::Io(_) => {
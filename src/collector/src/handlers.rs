@@ -5,13 +5,17 @@ use base64::Engine;
 use borsh::BorshDeserialize;
 use common::{RoundId, Sol, Token, Transaction, TransactionId, Updraft, User, UserId};
 use db::DataVersion;
-use msg_broker::Handler;
+use msg_broker::{Envelope, Handler, SendOptions};
+use serde_json::json;
+use solana_client::{rpc_config::RpcTransactionConfig, rpc_request::RpcRequest};
 use solana_signature::Signature;
 use solana_transaction_status_client_types::{
     EncodedConfirmedTransactionWithStatusMeta, EncodedTransaction, UiMessage, UiTransactionEncoding,
 };
 
-use crate::{AppContext, Msg, error::HandlerError};
+use crate::{
+    AppContext, MAX_RECHECKS, Msg, RECHECK_DELAY, RECHECK_HEADER, SLOT_HEADER, error::HandlerError,
+};
 
 pub struct RaydiumSwap {
     wallet_id: String,
@@ -135,10 +139,22 @@ impl Handler for Raydium {
         }
 
         let signature = Signature::from_str(&msg.signature).context("Failed to parse signautre")?;
+        let config = RpcTransactionConfig {
+            encoding: Some(UiTransactionEncoding::JsonParsed),
+            commitment: Some(ctx.solana_rpc_client.commitment()),
+            max_supported_transaction_version: None,
+        };
+        // `getTransaction` returns null until the transaction reaches the
+        // client's commitment. Requested as an option, so a response that
+        // fails to deserialize stays an error.
         let tx = ctx
             .solana_rpc_client
-            .get_transaction(&signature, UiTransactionEncoding::JsonParsed)
-            .await?;
+            .send::<Option<EncodedConfirmedTransactionWithStatusMeta>>(
+                RpcRequest::GetTransaction,
+                json!([signature.to_string(), config]),
+            )
+            .await?
+            .ok_or(HandlerError::NotFound)?;
         let swap = RaydiumSwap::parse(tx)?;
         ctx.db_client
             .upsert_user(User::new(&swap.wallet_id), DataVersion::init())
@@ -161,4 +177,40 @@ impl Handler for Raydium {
 
         Ok(())
     }
+
+    async fn handle_envelope(
+        &self,
+        ctx: Arc<AppContext>,
+        msg: Msg,
+        envelope: Envelope,
+    ) -> Result<(), Self::Error> {
+        let signature = msg.signature.clone();
+        let rechecks = envelope
+            .header(RECHECK_HEADER)
+            .and_then(|rechecks| rechecks.parse::<u32>().ok())
+            .unwrap_or(0);
+        match self.handle(ctx.clone(), msg).await {
+            Err(HandlerError::NotFound) if rechecks < MAX_RECHECKS => {
+                let mut options = SendOptions::new()
+                    .correlation_id(envelope.correlation_id())
+                    .header(RECHECK_HEADER, (rechecks + 1).to_string())
+                    .delay(RECHECK_DELAY);
+                if let Some(slot) = envelope.header(SLOT_HEADER) {
+                    options = options.header(SLOT_HEADER, slot);
+                }
+                ctx.publisher
+                    .send_with::<Raydium>(Msg { signature }, options)
+                    .await
+                    .map_err(Into::into)
+            }
+            res => res,
+        }
+    }
+
+    /// A transaction that invokes the program twice is logged twice. Rechecks
+    /// republish the same signature, so they are keyed apart.
+    fn idempotency_key(&self, msg: &Msg, envelope: &Envelope) -> Option<String> {
+        let rechecks = envelope.header(RECHECK_HEADER).unwrap_or("0");
+        Some(format!("{}:{}", msg.signature, rechecks))
+    }
 }
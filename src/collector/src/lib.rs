@@ -1,11 +1,16 @@
-use std::{pin::Pin, sync::Arc};
+use std::{sync::Arc, time::Duration};
 
 use anyhow::Context;
+pub use backfill::{BACKFILL_USAGE, Backfill, Bound, run_backfill};
 use bincode::{Decode, Encode};
 use db::{DbClient, DbConfig};
-use futures::StreamExt;
+use futures::{StreamExt, stream};
 use handlers::Raydium;
-use msg_broker::{MessageBroker, MessageHandler, Publisher};
+use msg_broker::{
+    BrokerStats, CircuitBreaker, DedupWindow, Handler, MessageBroker, MessageHandler,
+    PostgresDeadLetterStore, PostgresTransport, PublishError, Publisher, RateLimit, SendOptions,
+    ShutdownToken, Timeout,
+};
 use serde::Deserialize;
 use solana_client::{
     nonblocking::{pubsub_client::PubsubClient, rpc_client::RpcClient},
@@ -13,10 +18,13 @@ use solana_client::{
 };
 use solana_commitment_config::{CommitmentConfig, CommitmentLevel};
 use tokio::{
-    sync::mpsc::{UnboundedReceiver, unbounded_channel},
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    signal::unix::{SignalKind, signal},
     task::JoinHandle,
 };
 
+mod backfill;
 mod error;
 mod handlers;
 mod program;
@@ -25,49 +33,140 @@ mod program;
 pub struct SolanaConfig {
     pub rpc_uri: String,
     pub ws_uri: String,
+    /// Requests per second the RPC provider allows.
+    #[serde(default = "default_rpc_rate_limit")]
+    pub rpc_rate_limit: u32,
+    /// Seconds without a slot notification before the websocket counts as
+    /// stale and is reconnected.
+    #[serde(default = "default_ws_heartbeat_timeout_secs")]
+    pub ws_heartbeat_timeout_secs: u64,
+}
+
+fn default_rpc_rate_limit() -> u32 {
+    10
+}
+
+fn default_ws_heartbeat_timeout_secs() -> u64 {
+    30
 }
 
 #[derive(Debug, Deserialize)]
 pub struct AppConfig {
     pub db_config: DbConfig,
     pub solana_config: SolanaConfig,
+    /// Where to serve broker metrics in the Prometheus text format, like
+    /// `0.0.0.0:9100`. Not served when unset.
+    pub metrics_addr: Option<String>,
 }
 
 pub struct AppContext {
     pub db_client: DbClient,
     pub solana_rpc_client: RpcClient,
+    pub publisher: Publisher,
 }
 
 impl AppContext {
-    pub async fn init(config: &AppConfig) -> anyhow::Result<Self> {
+    /// Handlers publish through `publisher`, which the broker is built on, see
+    /// [`msg_broker::MessageBrokerBuilder::publisher`].
+    pub async fn init(config: &AppConfig, publisher: Publisher) -> anyhow::Result<Self> {
         Ok(AppContext {
             db_client: DbClient::connect(&config.db_config)
                 .await
                 .context("failed to connect to db")?,
             solana_rpc_client: RpcClient::new(config.solana_config.rpc_uri.clone()),
+            publisher,
         })
     }
 }
 
+/// Envelope header holding the slot a signature was seen in.
+pub const SLOT_HEADER: &str = "slot";
+
+/// Envelope header counting how often a signature was looked up again.
+pub const RECHECK_HEADER: &str = "rechecks";
+
+/// How long to wait before looking up a signature `getTransaction` did not
+/// return yet. Signatures are seen at `Processed` but only fetchable once
+/// their transaction is confirmed.
+pub const RECHECK_DELAY: Duration = Duration::from_secs(5);
+
+/// How often a signature is looked up again before it is dead lettered.
+pub const MAX_RECHECKS: u32 = 3;
+
+/// Programs whose logs are subscribed to, each with a handler.
+pub const PROGRAMS: [&str; 1] = [Raydium::PROGRAM_ID];
+
+/// How long to wait before reconnecting a lost websocket, doubling up to
+/// [`MAX_WS_BACKOFF`] while reconnects keep failing.
+const WS_BACKOFF: Duration = Duration::from_secs(1);
+
+const MAX_WS_BACKOFF: Duration = Duration::from_secs(60);
+
+/// How long to wait for each unsubscribe on a closing websocket, which may
+/// already be dead.
+const WS_UNSUBSCRIBE_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[derive(Debug, Decode, Encode)]
 pub struct Msg {
     signature: String,
 }
 
 pub async fn run(config: AppConfig) -> anyhow::Result<()> {
-    let ctx = AppContext::init(&config).await?;
-    let borker = MessageBroker::new(ctx, vec![MessageHandler::new(Raydium)]);
-
-    let publisher = borker.get_publisher();
+    let transport = PostgresTransport::connect(&config.db_config)
+        .await
+        .context("failed to connect broker transport")?;
+    let publisher = Publisher::new(Arc::new(transport));
+    let ctx = AppContext::init(&config, publisher.clone()).await?;
+    let dead_letters = PostgresDeadLetterStore::connect(&config.db_config)
+        .await
+        .context("failed to connect dead letter store")?;
+    let ws_db_client = DbClient::connect(&config.db_config)
+        .await
+        .context("failed to connect slot gap db")?;
+    let shutdown = ShutdownToken::new();
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            match shutdown_signal().await {
+                Ok(()) => {
+                    println!("shutdown signal received");
+                    shutdown.shutdown();
+                }
+                Err(err) => println!("WARN: not listening for shutdown signals: {:#}", err),
+            }
+        }
+    });
 
-    let (join_handle, mut unsubscribe_rx) = run_ws(&config.solana_config, publisher).await?;
-    borker.run().await;
+    let borker = MessageBroker::builder(ctx)
+        .handler(
+            MessageHandler::new(Raydium)
+                .with_rate_limit(RateLimit::per_second(config.solana_config.rpc_rate_limit))
+                .with_dedup(DedupWindow::new(Duration::from_secs(60), 10_000)),
+        )
+        .workers(4)
+        .middleware(Timeout::new(Duration::from_secs(30)))
+        .circuit_breaker(
+            Raydium::ROUTING_KEY,
+            CircuitBreaker::new(10, Duration::from_secs(60)),
+        )
+        .dead_letter_store(dead_letters)
+        .publisher(&publisher)
+        .shutdown(shutdown.clone())
+        .drain_timeout(Duration::from_secs(30))
+        .build();
 
-    while let Some((unsubscribe, name)) = unsubscribe_rx.recv().await {
-        println!("unsubscribing from {}", name);
-        unsubscribe().await
+    if let Some(addr) = config.metrics_addr.clone() {
+        let stats = borker.stats();
+        tokio::spawn(async move {
+            if let Err(err) = serve_metrics(&addr, stats).await {
+                println!("WARN: metrics server stopped: {:#}", err);
+            }
+        });
     }
 
+    let join_handle = run_ws(&config.solana_config, ws_db_client, publisher, shutdown);
+    borker.run().await;
+
     join_handle
         .await
         .context("join failed")?
@@ -76,86 +175,260 @@ pub async fn run(config: AppConfig) -> anyhow::Result<()> {
     Ok(())
 }
 
-pub async fn run_ws(
+/// Resolves on the first SIGINT or SIGTERM.
+async fn shutdown_signal() -> anyhow::Result<()> {
+    let mut terminate = signal(SignalKind::terminate()).context("failed to listen for SIGTERM")?;
+    tokio::select! {
+        res = tokio::signal::ctrl_c() => res.context("failed to listen for SIGINT")?,
+        _ = terminate.recv() => {}
+    }
+    Ok(())
+}
+
+/// Answers every connection on `addr` with the broker's stats.
+async fn serve_metrics(addr: &str, stats: BrokerStats) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("failed to bind metrics on {}", addr))?;
+    loop {
+        let (mut stream, _) = listener
+            .accept()
+            .await
+            .context("failed to accept metrics connection")?;
+        let body = stats.prometheus();
+        tokio::spawn(async move {
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request).await;
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            if let Err(err) = stream.write_all(response.as_bytes()).await {
+                println!("WARN: failed to write metrics: {}", err);
+            }
+        });
+    }
+}
+
+/// Publishes a message per Raydium log notification until `shutdown` fires.
+/// The subscription is opened again with backoff whenever the websocket drops
+/// or its slot notifications stop for longer than the heartbeat timeout, and
+/// the slots it may have missed in between are recorded as a slot gap.
+pub fn run_ws(
     config: &SolanaConfig,
+    db_client: DbClient,
     publisher: Publisher,
-) -> anyhow::Result<(
-    JoinHandle<Result<(), anyhow::Error>>,
-    UnboundedReceiver<(
-        Box<dyn FnOnce() -> Pin<Box<dyn Future<Output = ()> + Send>> + Send>,
-        &'static str,
-    )>,
-)> {
-    let (ready_sender, mut ready_receiver) = unbounded_channel::<()>();
-    let (unsubscribe_sender, unsubscribe_receiver) = unbounded_channel::<(_, &'static str)>();
-
-    let client = Arc::new(
-        PubsubClient::new(&config.ws_uri)
-            .await
-            .context("Failed to connect to ws")?,
-    );
+    shutdown: ShutdownToken,
+) -> JoinHandle<anyhow::Result<()>> {
+    let ws_uri = config.ws_uri.clone();
+    let heartbeat = Duration::from_secs(config.ws_heartbeat_timeout_secs);
+    tokio::spawn(async move {
+        let mut last_slot = None;
+        let mut backoff = WS_BACKOFF;
+        loop {
+            let seen = last_slot;
+            let err = match subscribe(
+                &ws_uri,
+                heartbeat,
+                &db_client,
+                &publisher,
+                &shutdown,
+                &mut last_slot,
+            )
+            .await?
+            {
+                Disconnect::Shutdown => return Ok(()),
+                Disconnect::Lost(err) => err,
+            };
+            if last_slot != seen {
+                backoff = WS_BACKOFF;
+            }
+            println!(
+                "WARN: lost log subscription after slot {}, reconnecting in {:?}: {:#}",
+                last_slot.map_or("none".to_string(), |slot| slot.to_string()),
+                backoff,
+                err
+            );
+            tokio::select! {
+                _ = shutdown.wait() => return Ok(()),
+                _ = tokio::time::sleep(backoff) => {}
+            }
+            backoff = (backoff * 2).min(MAX_WS_BACKOFF);
+        }
+    })
+}
 
-    let join_handler = tokio::spawn({
-        let ready_sender = ready_sender.clone();
-        let unsubscribe_sender = unsubscribe_sender.clone();
-        let client = client.clone();
+/// How a log subscription ended, errors that reconnecting cannot fix are
+/// returned instead.
+enum Disconnect {
+    Shutdown,
+    /// The websocket failed, closed or went quiet.
+    Lost(anyhow::Error),
+}
 
-        async move {
-            let (mut log_notifications, log_unsubscribe) = client
+/// Runs one log subscription per program in [`PROGRAMS`], merged into one
+/// stream, losing any of them loses all. A slot subscription on the same
+/// websocket serves as its heartbeat and moves `last_slot`. When the first slot
+/// after a lost connection skips past `last_slot + 1`, the slots from
+/// `last_slot` up to it are recorded as a gap, both ends included since either
+/// may have been seen only in part.
+///
+/// Every subscription made is unsubscribed from on the way out, best-effort
+/// since the websocket may already be gone.
+async fn subscribe(
+    ws_uri: &str,
+    heartbeat: Duration,
+    db_client: &DbClient,
+    publisher: &Publisher,
+    shutdown: &ShutdownToken,
+    last_slot: &mut Option<u64>,
+) -> anyhow::Result<Disconnect> {
+    let client = match PubsubClient::new(ws_uri).await {
+        Ok(client) => client,
+        Err(err) => return Ok(Disconnect::Lost(err.into())),
+    };
+    let mut unsubscribes = Vec::with_capacity(PROGRAMS.len() + 1);
+    let res = 'subscription: {
+        let mut slots = match client.slot_subscribe().await {
+            Ok((slots, slot_unsubscribe)) => {
+                unsubscribes.push(slot_unsubscribe);
+                slots
+            }
+            Err(err) => break 'subscription Ok(Disconnect::Lost(err.into())),
+        };
+        // Nodes take a single address per `Mentions` filter, so every program
+        // gets its own subscription. `None` marks the end of one of them.
+        let mut subscriptions = Vec::with_capacity(PROGRAMS.len());
+        for program_id in PROGRAMS {
+            let subscription = client
                 .logs_subscribe(
-                    RpcTransactionLogsFilter::All,
+                    RpcTransactionLogsFilter::Mentions(vec![program_id.to_string()]),
                     RpcTransactionLogsConfig {
                         commitment: Some(CommitmentConfig {
                             commitment: CommitmentLevel::Processed,
                         }),
                     },
                 )
-                .await
-                .context("Failed to log_subcribe")?;
-
-            ready_sender
-                .send(())
-                .context("failed to send ready signal")?;
-            unsubscribe_sender
-                .send((log_unsubscribe, "log"))
-                .map_err(|e| anyhow::anyhow!("failed to send log unsubribe: {e}"))?;
-            drop((ready_sender, unsubscribe_sender));
-
-            while let Some(log_info) = log_notifications.next().await {
-                if log_info.value.err.is_some() {
+                .await;
+            match subscription {
+                Ok((log_notifications, log_unsubscribe)) => {
+                    subscriptions.push(log_notifications.map(Some).chain(stream::iter([None])));
+                    unsubscribes.push(log_unsubscribe);
+                }
+                Err(err) => break 'subscription Ok(Disconnect::Lost(err.into())),
+            }
+        }
+        let mut log_notifications = stream::select_all(subscriptions);
+        let mut gap_from = *last_slot;
+        let stale = tokio::time::sleep(heartbeat);
+        tokio::pin!(stale);
+
+        loop {
+            let log_info = tokio::select! {
+                biased;
+                _ = shutdown.wait() => break Ok(Disconnect::Shutdown),
+                _ = &mut stale => {
+                    let err = anyhow::anyhow!("no slot notification within {:?}", heartbeat);
+                    break Ok(Disconnect::Lost(err));
+                }
+                slot_info = slots.next() => {
+                    let Some(slot_info) = slot_info else {
+                        break Ok(Disconnect::Lost(anyhow::anyhow!("slot subscription closed")));
+                    };
+                    stale.as_mut().reset(tokio::time::Instant::now() + heartbeat);
+                    let slot = slot_info.slot;
+                    if let Some(from_slot) = gap_from.take() {
+                        if slot > from_slot + 1 {
+                            record_gap(db_client, from_slot, slot).await;
+                        }
+                    }
+                    *last_slot = (*last_slot).max(Some(slot));
                     continue;
                 }
+                log_info = log_notifications.next() => match log_info {
+                    Some(Some(log_info)) => log_info,
+                    Some(None) | None => {
+                        break Ok(Disconnect::Lost(anyhow::anyhow!("log subscription closed")));
+                    }
+                },
+            };
+            if log_info.value.err.is_some() {
+                continue;
+            }
+            let slot = log_info.context.slot;
 
-                for log in log_info.value.logs {
-                    let mut log = log.split_whitespace();
+            for log in log_info.value.logs {
+                let mut log = log.split_whitespace();
 
-                    let tokens = log
-                        .next()
-                        .zip(log.next())
-                        .zip(log.next())
-                        .map(|((a, b), c)| (a, b, c));
+                let tokens = log
+                    .next()
+                    .zip(log.next())
+                    .zip(log.next())
+                    .map(|((a, b), c)| (a, b, c));
 
-                    if let Some(("Program", id, "invoke")) = tokens {
-                        match id {
-                            Raydium::PROGRAM_ID => {
-                                publisher
-                                    .send::<Raydium>(Msg {
+                if let Some(("Program", id, "invoke")) = tokens {
+                    let res = match id {
+                        Raydium::PROGRAM_ID => {
+                            publisher
+                                .try_send_with::<Raydium>(
+                                    Msg {
                                         signature: log_info.value.signature.to_string(),
-                                    })
-                                    .await
-                            }
-                            _ => continue,
+                                    },
+                                    SendOptions::new().header(SLOT_HEADER, slot.to_string()),
+                                )
+                                .await
+                        }
+                        _ => continue,
+                    };
+                    match res {
+                        Ok(()) => {}
+                        Err(PublishError::Closed) if shutdown.is_shutdown() => {
+                            break 'subscription Ok(Disconnect::Shutdown);
                         }
+                        Err(PublishError::Closed) => {
+                            break 'subscription Err(anyhow::anyhow!("broker closed"));
+                        }
+                        Err(err) => println!(
+                            "WARN: failed to publish {}: {}",
+                            log_info.value.signature, err
+                        ),
                     }
                 }
             }
-            anyhow::Ok(())
         }
-    });
-
-    drop((ready_sender, unsubscribe_sender));
-    while let Some(_) = ready_receiver.recv().await {}
-    drop(ready_receiver);
+    };
+    println!("unsubscribing from slots and logs");
+    for unsubscribe in unsubscribes {
+        if tokio::time::timeout(WS_UNSUBSCRIBE_TIMEOUT, unsubscribe())
+            .await
+            .is_err()
+        {
+            println!(
+                "WARN: unsubscribe timed out after {:?}",
+                WS_UNSUBSCRIBE_TIMEOUT
+            );
+        }
+    }
+    res
+}
 
-    Ok((join_handler, unsubscribe_receiver))
+async fn record_gap(db_client: &DbClient, from_slot: u64, to_slot: u64) {
+    println!(
+        "WARN: log subscription may have missed slots {} to {}",
+        from_slot, to_slot
+    );
+    let res = match (i64::try_from(from_slot), i64::try_from(to_slot)) {
+        (Ok(from_slot), Ok(to_slot)) => db_client
+            .insert_slot_gap(from_slot, to_slot)
+            .await
+            .map_err(anyhow::Error::from),
+        _ => Err(anyhow::anyhow!("slot out of range")),
+    };
+    if let Err(err) = res {
+        println!(
+            "WARN: failed to record slot gap {} to {}: {:#}",
+            from_slot, to_slot, err
+        );
+    }
 }
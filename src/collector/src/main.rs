@@ -1,5 +1,5 @@
 use anyhow::Context;
-use collector::AppConfig;
+use collector::{AppConfig, BACKFILL_USAGE, Backfill};
 use config::{Config, Environment};
 use dotenv::dotenv;
 
@@ -18,5 +18,13 @@ async fn main() -> anyhow::Result<()> {
         .try_deserialize()
         .context("config deserialize")?;
 
-    collector::run(config).await
+    let mut args = std::env::args().skip(1);
+    match args.next().as_deref() {
+        None => collector::run(config).await,
+        Some("backfill") => {
+            let backfill = Backfill::from_args(args).context(BACKFILL_USAGE)?;
+            collector::run_backfill(config, backfill).await
+        }
+        Some(command) => anyhow::bail!("unknown command {}\n{}", command, BACKFILL_USAGE),
+    }
 }
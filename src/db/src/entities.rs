@@ -1,3 +1,5 @@
+use std::{collections::BTreeMap, time::SystemTime};
+
 use serde::{Deserialize, Serialize};
 pub use tokio_postgres::types::Json;
 use uuid::Uuid;
@@ -92,3 +94,50 @@ pub struct TransactionDataV1 {
 pub enum Dex {
     Raydium,
 }
+
+////////////////////////////////////////////////////////////////////////////////
+// DEAD LETTER
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone)]
+pub struct DeadLetter {
+    pub dead_letter_id: Uuid,
+    pub routing_key: String,
+    pub payload: Vec<u8>,
+    pub schema_version: i32,
+    pub error: String,
+    pub attempts: i32,
+    pub failed_at: SystemTime,
+    pub handler: Option<String>,
+    /// Envelope fields restored on replay, `None` for dead letters stored
+    /// before they were kept.
+    pub correlation_id: Option<Uuid>,
+    pub headers: Option<Json<BTreeMap<String, String>>>,
+    pub partition_key: Option<String>,
+    pub priority: Option<i16>,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// BROKER JOB
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone)]
+pub struct BrokerJob {
+    pub job_id: i64,
+    pub routing_key: String,
+    pub message: Vec<u8>,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// BACKFILL
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone)]
+pub struct Backfill {
+    pub backfill_id: Uuid,
+    pub program_id: String,
+    /// The oldest signature published so far, paging resumes before it.
+    pub cursor: Option<String>,
+    pub published: i64,
+    pub done: bool,
+}
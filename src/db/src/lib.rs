@@ -1,7 +1,7 @@
-use std::marker::PhantomData;
+use std::{marker::PhantomData, time::Duration};
 
 use anyhow::Context;
-use entities::{Round, Transaction, User};
+use entities::{Backfill, BrokerJob, DeadLetter, Round, Transaction, User};
 use error::DbError;
 use serde::Deserialize;
 use tokio_postgres::{Config, NoTls, Row};
@@ -72,6 +72,13 @@ impl DbClient {
         Ok(DbClient { inner: client })
     }
 
+    /// Creates the tables of the message broker and the collector, with their
+    /// indexes, where they are missing. See `schema.sql`.
+    pub async fn migrate(&self) -> Result<()> {
+        self.inner.batch_execute(include_str!("schema.sql")).await?;
+        Ok(())
+    }
+
     pub async fn get_user<T>(
         &self,
         user_id: impl Into<Uuid>,
@@ -244,6 +251,336 @@ impl DbClient {
             .map(transaction_from_row)
             .collect::<Result<_>>()
     }
+
+    pub async fn insert_dead_letter(&self, dead_letter: impl Into<DeadLetter>) -> Result<()> {
+        let dead_letter = dead_letter.into();
+        self.inner
+            .execute(
+                r#"
+                    INSERT INTO dead_letters (
+                        dead_letter_id,
+                        routing_key,
+                        payload,
+                        error,
+                        attempts,
+                        failed_at,
+                        handler,
+                        schema_version,
+                        correlation_id,
+                        headers,
+                        partition_key,
+                        priority
+                    )
+                    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)
+                "#,
+                &[
+                    &dead_letter.dead_letter_id,
+                    &dead_letter.routing_key,
+                    &dead_letter.payload,
+                    &dead_letter.error,
+                    &dead_letter.attempts,
+                    &dead_letter.failed_at,
+                    &dead_letter.handler,
+                    &dead_letter.schema_version,
+                    &dead_letter.correlation_id,
+                    &dead_letter.headers,
+                    &dead_letter.partition_key,
+                    &dead_letter.priority,
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn get_dead_letter<T>(&self, dead_letter_id: impl Into<Uuid>) -> Result<Option<T>>
+    where
+        T: From<DeadLetter>,
+    {
+        let dead_letter_id = dead_letter_id.into();
+        let row = self
+            .inner
+            .query_opt(
+                r#"
+                    SELECT
+                        dead_letter_id,
+                        routing_key,
+                        payload,
+                        error,
+                        attempts,
+                        failed_at,
+                        handler,
+                        schema_version,
+                        correlation_id,
+                        headers,
+                        partition_key,
+                        priority
+                    FROM dead_letters
+                    WHERE dead_letter_id = $1
+                "#,
+                &[&dead_letter_id],
+            )
+            .await?;
+        row.map(dead_letter_from_row).transpose()
+    }
+
+    pub async fn get_dead_letters<T>(&self) -> Result<Vec<T>>
+    where
+        T: From<DeadLetter>,
+    {
+        let rows = self
+            .inner
+            .query(
+                r#"
+                    SELECT
+                        dead_letter_id,
+                        routing_key,
+                        payload,
+                        error,
+                        attempts,
+                        failed_at,
+                        handler,
+                        schema_version,
+                        correlation_id,
+                        headers,
+                        partition_key,
+                        priority
+                    FROM dead_letters
+                    ORDER BY failed_at
+                "#,
+                &[],
+            )
+            .await?;
+
+        rows.into_iter()
+            .map(dead_letter_from_row)
+            .collect::<Result<_>>()
+    }
+
+    pub async fn delete_dead_letter<T>(&self, dead_letter_id: impl Into<Uuid>) -> Result<Option<T>>
+    where
+        T: From<DeadLetter>,
+    {
+        let dead_letter_id = dead_letter_id.into();
+        let row = self
+            .inner
+            .query_opt(
+                r#"
+                    DELETE FROM dead_letters
+                    WHERE dead_letter_id = $1
+                    RETURNING
+                        dead_letter_id,
+                        routing_key,
+                        payload,
+                        error,
+                        attempts,
+                        failed_at,
+                        handler,
+                        schema_version,
+                        correlation_id,
+                        headers,
+                        partition_key,
+                        priority
+                "#,
+                &[&dead_letter_id],
+            )
+            .await?;
+        row.map(dead_letter_from_row).transpose()
+    }
+
+    /// Jobs with a lower `priority` are claimed first.
+    pub async fn enqueue_broker_job(
+        &self,
+        routing_key: &str,
+        message: &[u8],
+        priority: i16,
+        delay: Duration,
+    ) -> Result<()> {
+        self.inner
+            .execute(
+                r#"
+                    INSERT INTO broker_jobs (
+                        routing_key,
+                        message,
+                        priority,
+                        available_at,
+                        created_at
+                    )
+                    VALUES ($1, $2, $3, NOW() + make_interval(secs => $4), NOW())
+                "#,
+                &[&routing_key, &message, &priority, &delay.as_secs_f64()],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Claims the oldest available job with the lowest priority for `lease`. A job that is not
+    /// deleted or rescheduled before its lease runs out is claimed again.
+    pub async fn claim_broker_job(&self, lease: Duration) -> Result<Option<BrokerJob>> {
+        let row = self
+            .inner
+            .query_opt(
+                r#"
+                    UPDATE broker_jobs SET
+                        locked_until = NOW() + make_interval(secs => $1)
+                    WHERE job_id = (
+                        SELECT job_id
+                        FROM broker_jobs
+                        WHERE available_at <= NOW()
+                            AND (locked_until IS NULL OR locked_until < NOW())
+                        ORDER BY priority, job_id
+                        FOR UPDATE SKIP LOCKED
+                        LIMIT 1
+                    )
+                    RETURNING
+                        job_id,
+                        routing_key,
+                        message
+                "#,
+                &[&lease.as_secs_f64()],
+            )
+            .await?;
+        row.map(broker_job_from_row).transpose()
+    }
+
+    pub async fn reschedule_broker_job(
+        &self,
+        job_id: i64,
+        message: &[u8],
+        delay: Duration,
+    ) -> Result<()> {
+        self.inner
+            .execute(
+                r#"
+                    UPDATE broker_jobs SET
+                        message = $2,
+                        available_at = NOW() + make_interval(secs => $3),
+                        locked_until = NULL
+                    WHERE job_id = $1
+                "#,
+                &[&job_id, &message, &delay.as_secs_f64()],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Moves a job whose message cannot be read to the dead letters in one
+    /// statement. The raw message becomes the payload, with schema version 0
+    /// since no handler can decode it.
+    pub async fn dead_letter_broker_job(
+        &self,
+        job_id: i64,
+        dead_letter_id: impl Into<Uuid>,
+        error: &str,
+    ) -> Result<()> {
+        let dead_letter_id = dead_letter_id.into();
+        self.inner
+            .execute(
+                r#"
+                    WITH job AS (
+                        DELETE FROM broker_jobs
+                        WHERE job_id = $1
+                        RETURNING routing_key, message
+                    )
+                    INSERT INTO dead_letters (
+                        dead_letter_id,
+                        routing_key,
+                        payload,
+                        error,
+                        attempts,
+                        failed_at,
+                        schema_version
+                    )
+                    SELECT $2, routing_key, message, $3, 0, NOW(), 0
+                    FROM job
+                "#,
+                &[&job_id, &dead_letter_id, &error],
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn delete_broker_job(&self, job_id: i64) -> Result<()> {
+        self.inner
+            .execute(
+                r#"
+                    DELETE FROM broker_jobs
+                    WHERE job_id = $1
+                "#,
+                &[&job_id],
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn get_backfill(&self, backfill_id: impl Into<Uuid>) -> Result<Option<Backfill>> {
+        let backfill_id = backfill_id.into();
+        let row = self
+            .inner
+            .query_opt(
+                r#"
+                    SELECT
+                        backfill_id,
+                        program_id,
+                        cursor,
+                        published,
+                        done
+                    FROM backfills
+                    WHERE backfill_id = $1
+                "#,
+                &[&backfill_id],
+            )
+            .await?;
+        row.map(backfill_from_row).transpose()
+    }
+
+    pub async fn upsert_backfill(&self, backfill: &Backfill) -> Result<()> {
+        self.inner
+            .execute(
+                r#"
+                    INSERT INTO backfills (
+                        backfill_id,
+                        program_id,
+                        cursor,
+                        published,
+                        done,
+                        updated_at
+                    )
+                    VALUES ($1, $2, $3, $4, $5, NOW())
+                    ON CONFLICT (backfill_id) DO UPDATE SET
+                        cursor = EXCLUDED.cursor,
+                        published = EXCLUDED.published,
+                        done = EXCLUDED.done,
+                        updated_at = EXCLUDED.updated_at
+                "#,
+                &[
+                    &backfill.backfill_id,
+                    &backfill.program_id,
+                    &backfill.cursor,
+                    &backfill.published,
+                    &backfill.done,
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Records that slots `from_slot` through `to_slot` may have been missed.
+    pub async fn insert_slot_gap(&self, from_slot: i64, to_slot: i64) -> Result<()> {
+        self.inner
+            .execute(
+                r#"
+                    INSERT INTO slot_gaps (
+                        from_slot,
+                        to_slot,
+                        detected_at
+                    )
+                    VALUES ($1, $2, NOW())
+                "#,
+                &[&from_slot, &to_slot],
+            )
+            .await?;
+        Ok(())
+    }
 }
 
 fn user_from_row<T>(row: Row) -> Result<(T, DataVersion<User>)>
@@ -283,3 +620,42 @@ where
     };
     Ok(T::from(transaction))
 }
+
+fn dead_letter_from_row<T>(row: Row) -> Result<T>
+where
+    T: From<DeadLetter>,
+{
+    let dead_letter = DeadLetter {
+        dead_letter_id: row.try_get(0)?,
+        routing_key: row.try_get(1)?,
+        payload: row.try_get(2)?,
+        error: row.try_get(3)?,
+        attempts: row.try_get(4)?,
+        failed_at: row.try_get(5)?,
+        handler: row.try_get(6)?,
+        schema_version: row.try_get(7)?,
+        correlation_id: row.try_get(8)?,
+        headers: row.try_get(9)?,
+        partition_key: row.try_get(10)?,
+        priority: row.try_get(11)?,
+    };
+    Ok(T::from(dead_letter))
+}
+
+fn broker_job_from_row(row: Row) -> Result<BrokerJob> {
+    Ok(BrokerJob {
+        job_id: row.try_get(0)?,
+        routing_key: row.try_get(1)?,
+        message: row.try_get(2)?,
+    })
+}
+
+fn backfill_from_row(row: Row) -> Result<Backfill> {
+    Ok(Backfill {
+        backfill_id: row.try_get(0)?,
+        program_id: row.try_get(1)?,
+        cursor: row.try_get(2)?,
+        published: row.try_get(3)?,
+        done: row.try_get(4)?,
+    })
+}
@@ -0,0 +1,59 @@
+-- Tables of the message broker and the collector, see `DbClient::migrate`.
+-- Every statement can run again on a database that already has them.
+
+-- Queue of `PostgresTransport`. A job is claimed by setting `locked_until`,
+-- and deleted once handled.
+CREATE TABLE IF NOT EXISTS broker_jobs (
+    job_id BIGSERIAL PRIMARY KEY,
+    routing_key TEXT NOT NULL,
+    message BYTEA NOT NULL,
+    priority SMALLINT NOT NULL DEFAULT 1,
+    available_at TIMESTAMPTZ NOT NULL,
+    locked_until TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL
+);
+
+-- Claims walk jobs by priority, then in the order they were enqueued.
+CREATE INDEX IF NOT EXISTS broker_jobs_claim_idx
+    ON broker_jobs (priority, job_id);
+
+CREATE TABLE IF NOT EXISTS dead_letters (
+    dead_letter_id UUID PRIMARY KEY,
+    routing_key TEXT NOT NULL,
+    payload BYTEA NOT NULL,
+    error TEXT NOT NULL,
+    attempts INT NOT NULL,
+    failed_at TIMESTAMPTZ NOT NULL,
+    handler TEXT,
+    schema_version INT NOT NULL
+);
+
+-- Envelope fields restored on replay, null for older dead letters.
+ALTER TABLE dead_letters
+    ADD COLUMN IF NOT EXISTS correlation_id UUID,
+    ADD COLUMN IF NOT EXISTS headers JSONB,
+    ADD COLUMN IF NOT EXISTS partition_key TEXT,
+    ADD COLUMN IF NOT EXISTS priority SMALLINT;
+
+CREATE INDEX IF NOT EXISTS dead_letters_failed_at_idx
+    ON dead_letters (failed_at);
+
+-- Progress of each collector backfill, `cursor` is the oldest signature
+-- published so far.
+CREATE TABLE IF NOT EXISTS backfills (
+    backfill_id UUID PRIMARY KEY,
+    program_id TEXT NOT NULL,
+    cursor TEXT,
+    published BIGINT NOT NULL,
+    done BOOLEAN NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL
+);
+
+-- Slots the collector's subscription may have missed, for a backfill to
+-- cover.
+CREATE TABLE IF NOT EXISTS slot_gaps (
+    gap_id BIGSERIAL PRIMARY KEY,
+    from_slot BIGINT NOT NULL,
+    to_slot BIGINT NOT NULL,
+    detected_at TIMESTAMPTZ NOT NULL
+);
@@ -0,0 +1,112 @@
+use std::{marker::PhantomData, sync::Arc, time::Duration};
+
+use bincode::error::DecodeError;
+
+use crate::{
+    Decoders, HandlerError, Payload, RetryPolicy, Route,
+    handler_trait::{BatchWrapper, InnerBatchHandler},
+};
+
+/// Like [`crate::Handler`] but takes up to a whole batch of messages at once.
+pub trait BatchHandler: Send + Sync + 'static {
+    type Context;
+    type Error: From<DecodeError> + Into<HandlerError<Self::Error>>;
+    /// See [`crate::Handler::Msg`].
+    type Msg: Payload + Send + Sync;
+
+    const ROUTING_KEY: &str;
+
+    /// See [`crate::Handler::SCHEMA_VERSION`].
+    const SCHEMA_VERSION: u32 = 1;
+
+    /// Returns one result per message, in the order they were given. Messages
+    /// left without a result are retried as if they failed with a transient
+    /// error.
+    fn handle_batch(
+        &self,
+        ctx: Arc<Self::Context>,
+        msgs: Vec<Self::Msg>,
+    ) -> impl Future<Output = Vec<Result<(), Self::Error>>> + Send;
+
+    /// Decoders for messages published with an older
+    /// [`BatchHandler::SCHEMA_VERSION`].
+    fn decoders() -> Decoders<Self::Msg> {
+        Decoders::new()
+    }
+}
+
+/// The [`Route`] of batch handler `T`, for publishing to it.
+pub struct Batched<T>(PhantomData<T>);
+
+impl<T> Route for Batched<T>
+where
+    T: BatchHandler,
+{
+    type Msg = T::Msg;
+
+    const ROUTING_KEY: &str = T::ROUTING_KEY;
+    const SCHEMA_VERSION: u32 = T::SCHEMA_VERSION;
+}
+
+/// A [`BatchHandler`] registered with a broker. Batches of one routing key are
+/// handled one at a time, and middleware does not run for them. A batch
+/// handler takes every message for its routing key, other handlers subscribed
+/// to the key never see them.
+pub struct BatchMessageHandler<Ctx, Err>
+where
+    Ctx: Send + Sync + 'static,
+    Err: Send + Sync + 'static,
+{
+    pub(crate) routing_key: &'static str,
+    pub(crate) name: &'static str,
+    pub(crate) handler: Arc<dyn InnerBatchHandler<Context = Ctx, Error = Err>>,
+    pub(crate) retry_policy: RetryPolicy,
+    pub(crate) max_batch_size: usize,
+    pub(crate) linger: Duration,
+}
+
+impl<Ctx, Err> BatchMessageHandler<Ctx, Err>
+where
+    Ctx: Send + Sync + 'static,
+    Err: From<DecodeError> + Into<HandlerError<Err>> + Send + Sync + 'static,
+{
+    pub fn new<T>(handler: T) -> Self
+    where
+        T: BatchHandler<Context = Ctx, Error = Err>,
+    {
+        BatchMessageHandler {
+            routing_key: T::ROUTING_KEY,
+            name: std::any::type_name::<T>(),
+            handler: Arc::new(BatchWrapper::new(handler)),
+            retry_policy: RetryPolicy::default(),
+            max_batch_size: 100,
+            linger: Duration::from_millis(50),
+        }
+    }
+
+    /// Overrides how transient errors from this handler are retried, per
+    /// message.
+    pub fn with_retry(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// The most messages handed to the handler at once.
+    pub fn max_batch_size(mut self, max_batch_size: usize) -> Self {
+        self.max_batch_size = max_batch_size.max(1);
+        self
+    }
+
+    /// How long to wait for a batch to fill up after its first message.
+    pub fn linger(mut self, linger: Duration) -> Self {
+        self.linger = linger;
+        self
+    }
+
+    /// Identifies the handler in dead letters, defaults to the handler's type
+    /// name. See [`crate::MessageHandler::with_name`].
+    pub fn with_name(mut self, name: &'static str) -> Self {
+        self.name = name;
+        self
+    }
+}
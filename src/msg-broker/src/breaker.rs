@@ -0,0 +1,196 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+/// How often messages for a paused routing key are checked again.
+const PAUSE_RECHECK: Duration = Duration::from_secs(1);
+
+/// Stops dispatching a routing key after repeated transient failures, see
+/// [`crate::MessageBrokerBuilder::circuit_breaker`]. Fatal errors are about
+/// the message rather than whatever the handler depends on, so they do not
+/// count.
+#[derive(Debug, Clone, Copy)]
+pub struct CircuitBreaker {
+    failures: usize,
+    window: Duration,
+    probe_interval: Duration,
+}
+
+impl CircuitBreaker {
+    /// Opens after `failures` transient failures within `window`.
+    pub fn new(failures: usize, window: Duration) -> Self {
+        CircuitBreaker {
+            failures: failures.max(1),
+            window,
+            probe_interval: Duration::from_secs(30),
+        }
+    }
+
+    /// How long an open breaker waits before letting a single probe message
+    /// through, 30 seconds by default.
+    pub fn probe_interval(mut self, probe_interval: Duration) -> Self {
+        self.probe_interval = probe_interval;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    /// Messages are dispatched.
+    Closed,
+    /// Messages are parked on the queue until the next probe.
+    Open,
+    /// A probe is being handled, its result closes or reopens the breaker.
+    HalfOpen,
+    /// Paused with [`Admin::pause`], messages are parked until resumed.
+    Paused,
+}
+
+/// Pauses, resumes and inspects routing keys of a running broker, see
+/// [`crate::MessageBroker::admin`].
+#[derive(Clone)]
+pub struct Admin {
+    breakers: Arc<Breakers>,
+}
+
+impl Admin {
+    pub(crate) fn new(breakers: Arc<Breakers>) -> Self {
+        Admin { breakers }
+    }
+
+    /// Stops dispatching `routing_key`. Its messages stay on the queue.
+    pub fn pause(&self, routing_key: &str) {
+        self.breakers.key(routing_key, |key| key.paused = true);
+        println!("WARN: paused {}", routing_key);
+    }
+
+    /// Dispatches `routing_key` again, closing its breaker.
+    pub fn resume(&self, routing_key: &str) {
+        self.breakers.key(routing_key, |key| {
+            *key = Key::default();
+        });
+        println!("WARN: resumed {}", routing_key);
+    }
+
+    pub fn state(&self, routing_key: &str) -> BreakerState {
+        self.breakers.key(routing_key, |key| match key.paused {
+            true => BreakerState::Paused,
+            false => key.state,
+        })
+    }
+}
+
+/// Breaker state of every routing key.
+pub(crate) struct Breakers {
+    configs: HashMap<String, CircuitBreaker>,
+    keys: Mutex<HashMap<String, Key>>,
+}
+
+struct Key {
+    state: BreakerState,
+    paused: bool,
+    /// When recent transient failures happened, oldest first.
+    failures: VecDeque<Instant>,
+    /// When the breaker opened or last let a probe through.
+    opened_at: Instant,
+}
+
+impl Default for Key {
+    fn default() -> Self {
+        Key {
+            state: BreakerState::Closed,
+            paused: false,
+            failures: VecDeque::new(),
+            opened_at: Instant::now(),
+        }
+    }
+}
+
+impl Breakers {
+    pub(crate) fn new(configs: HashMap<String, CircuitBreaker>) -> Self {
+        Breakers {
+            configs,
+            keys: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn key<R>(&self, routing_key: &str, f: impl FnOnce(&mut Key) -> R) -> R {
+        let mut keys = self.keys.lock().expect("poisoned");
+        f(keys.entry(routing_key.to_string()).or_default())
+    }
+
+    /// How long to park a message for `routing_key`, or `None` to dispatch
+    /// it. An open breaker lets one message through as a probe once every
+    /// probe interval.
+    pub(crate) fn admit(&self, routing_key: &str) -> Option<Duration> {
+        let config = self.configs.get(routing_key);
+        let mut keys = self.keys.lock().expect("poisoned");
+        if config.is_none() && !keys.contains_key(routing_key) {
+            return None;
+        }
+        let key = keys.entry(routing_key.to_string()).or_default();
+        if key.paused {
+            return Some(PAUSE_RECHECK);
+        }
+        let config = config?;
+        if key.state == BreakerState::Closed {
+            return None;
+        }
+        let probe_at = key.opened_at + config.probe_interval;
+        let now = Instant::now();
+        if now < probe_at {
+            return Some(probe_at - now);
+        }
+        key.state = BreakerState::HalfOpen;
+        key.opened_at = now;
+        None
+    }
+
+    /// Records how a message for `routing_key` went, `failed` when it failed
+    /// with a transient error.
+    pub(crate) fn record(&self, routing_key: &str, failed: bool) {
+        let Some(config) = self.configs.get(routing_key) else {
+            return;
+        };
+        self.key(routing_key, |key| {
+            let now = Instant::now();
+            match (key.state, failed) {
+                (BreakerState::HalfOpen, false) => {
+                    println!("WARN: probe succeeded, closing breaker for {}", routing_key);
+                    key.state = BreakerState::Closed;
+                    key.failures.clear();
+                }
+                (BreakerState::HalfOpen, true) => {
+                    println!("WARN: probe failed, reopening breaker for {}", routing_key);
+                    key.state = BreakerState::Open;
+                    key.opened_at = now;
+                }
+                (BreakerState::Closed, true) => {
+                    key.failures.push_back(now);
+                    while let Some(&at) = key.failures.front() {
+                        match now.duration_since(at) > config.window {
+                            true => key.failures.pop_front(),
+                            false => break,
+                        };
+                    }
+                    if key.failures.len() >= config.failures {
+                        println!(
+                            "WARN: {} failures within {:?}, opening breaker for {}",
+                            key.failures.len(),
+                            config.window,
+                            routing_key
+                        );
+                        key.state = BreakerState::Open;
+                        key.opened_at = now;
+                        key.failures.clear();
+                    }
+                }
+                _ => {}
+            }
+        })
+    }
+}
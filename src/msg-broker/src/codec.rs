@@ -0,0 +1,197 @@
+use std::{collections::HashMap, fmt};
+
+use bincode::error::{DecodeError, EncodeError};
+use borsh::{BorshDeserialize, BorshSerialize};
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::{CODEC_HEADER, Envelope};
+
+#[derive(thiserror::Error, Debug)]
+pub enum CodecError {
+    #[error(transparent)]
+    BincodeEncode(#[from] EncodeError),
+    #[error(transparent)]
+    BincodeDecode(#[from] DecodeError),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Borsh(#[from] std::io::Error),
+    #[error("no decoder for schema version {0}")]
+    UnsupportedVersion(u32),
+    #[error("payload encoded with {found}, expected {expected}")]
+    UnexpectedCodec {
+        expected: &'static str,
+        found: String,
+    },
+}
+
+/// Turns handler messages into payload bytes and back.
+pub trait Codec<T>: Send + Sync + 'static {
+    /// Sent in the [`crate::CODEC_HEADER`] of every message.
+    const NAME: &str;
+
+    fn encode(msg: &T) -> Result<Vec<u8>, CodecError>;
+
+    fn decode(data: &[u8]) -> Result<T, CodecError>;
+}
+
+/// A message type and how it is encoded on the queue. Every `bincode` type is
+/// a payload encoded with [`Bincode`], other types implement it to pick their
+/// codec, like `impl Payload for Swap { type Codec = Json; }`.
+pub trait Payload: Sized {
+    type Codec: Codec<Self>;
+}
+
+impl<T> Payload for T
+where
+    T: bincode::Encode + bincode::Decode<()>,
+{
+    type Codec = Bincode;
+}
+
+/// `bincode` with its standard config.
+pub struct Bincode;
+
+impl<T> Codec<T> for Bincode
+where
+    T: bincode::Encode + bincode::Decode<()>,
+{
+    const NAME: &str = "bincode";
+
+    fn encode(msg: &T) -> Result<Vec<u8>, CodecError> {
+        Ok(bincode::encode_to_vec(msg, bincode::config::standard())?)
+    }
+
+    fn decode(data: &[u8]) -> Result<T, CodecError> {
+        let (msg, _) = bincode::decode_from_slice(data, bincode::config::standard())?;
+        Ok(msg)
+    }
+}
+
+/// `serde_json`, readable in the queue and tolerant of added optional fields.
+pub struct Json;
+
+impl<T> Codec<T> for Json
+where
+    T: Serialize + DeserializeOwned,
+{
+    const NAME: &str = "json";
+
+    fn encode(msg: &T) -> Result<Vec<u8>, CodecError> {
+        Ok(serde_json::to_vec(msg)?)
+    }
+
+    fn decode(data: &[u8]) -> Result<T, CodecError> {
+        Ok(serde_json::from_slice(data)?)
+    }
+}
+
+/// `borsh`, the encoding most Solana programs use.
+pub struct Borsh;
+
+impl<T> Codec<T> for Borsh
+where
+    T: BorshSerialize + BorshDeserialize,
+{
+    const NAME: &str = "borsh";
+
+    fn encode(msg: &T) -> Result<Vec<u8>, CodecError> {
+        Ok(borsh::to_vec(msg)?)
+    }
+
+    fn decode(data: &[u8]) -> Result<T, CodecError> {
+        Ok(borsh::from_slice(data)?)
+    }
+}
+
+type Decoder<T> = Box<dyn Fn(&[u8]) -> Result<T, CodecError> + Send + Sync>;
+
+/// Fails unless `codec`, the [`crate::CODEC_HEADER`] of a message, is `expected`.
+/// Messages without the header are assumed to match.
+fn check_codec(expected: &'static str, codec: Option<&str>) -> Result<(), CodecError> {
+    match codec {
+        Some(found) if found != expected => Err(CodecError::UnexpectedCodec {
+            expected,
+            found: found.to_string(),
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Decoders for payloads published with an older schema version, see
+/// [`crate::Handler::decoders`].
+pub struct Decoders<T> {
+    decoders: HashMap<u32, (&'static str, Decoder<T>)>,
+}
+
+impl<T> Decoders<T> {
+    pub fn new() -> Self {
+        Decoders {
+            decoders: HashMap::new(),
+        }
+    }
+
+    /// Decodes version `version` payloads as `Old` and upgrades them with
+    /// `upgrade`.
+    pub fn register<Old>(
+        mut self,
+        version: u32,
+        upgrade: impl Fn(Old) -> T + Send + Sync + 'static,
+    ) -> Self
+    where
+        Old: Payload + 'static,
+    {
+        let decoder = move |data: &[u8]| Old::Codec::decode(data).map(&upgrade);
+        self.decoders
+            .insert(version, (Old::Codec::NAME, Box::new(decoder)));
+        self
+    }
+
+    fn decode(&self, version: u32, codec: Option<&str>, data: &[u8]) -> Result<T, CodecError> {
+        match self.decoders.get(&version) {
+            Some((name, decoder)) => {
+                check_codec(name, codec)?;
+                decoder(data)
+            }
+            None => Err(CodecError::UnsupportedVersion(version)),
+        }
+    }
+}
+
+impl<T> Default for Decoders<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> fmt::Debug for Decoders<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.decoders.keys()).finish()
+    }
+}
+
+/// Decodes a payload of any supported version as `T`.
+pub(crate) struct Decoding<T> {
+    version: u32,
+    decoders: Decoders<T>,
+}
+
+impl<T> Decoding<T>
+where
+    T: Payload,
+{
+    pub(crate) fn new(version: u32, decoders: Decoders<T>) -> Self {
+        Decoding { version, decoders }
+    }
+
+    /// Decodes the payload of a message with `envelope`, by its schema
+    /// version and codec headers.
+    pub(crate) fn decode(&self, envelope: &Envelope, data: &[u8]) -> Result<T, CodecError> {
+        let version = envelope.schema_version();
+        let codec = envelope.header(CODEC_HEADER);
+        match version == self.version {
+            true => check_codec(T::Codec::NAME, codec).and_then(|()| T::Codec::decode(data)),
+            false => self.decoders.decode(version, codec, data),
+        }
+    }
+}
@@ -0,0 +1,261 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use db::{DbClient, DbConfig, entities::Json, error::DbError};
+use uuid::Uuid;
+
+use crate::{
+    CODEC_HEADER, Codec, CodecError, Message, Payload, Priority, PublishError, Publisher, Route,
+    handler_trait::BoxFuture,
+};
+
+/// A message whose handler failed with a fatal error.
+#[derive(Debug, Clone)]
+pub struct DeadLetter {
+    pub id: Uuid,
+    pub routing_key: String,
+    pub data: Vec<u8>,
+    /// See [`crate::Envelope::schema_version`].
+    pub schema_version: u32,
+    /// The handler that failed, `None` if no handler was subscribed.
+    pub handler: Option<String>,
+    pub error: String,
+    pub attempts: u32,
+    pub failed_at: SystemTime,
+    /// See [`crate::Envelope::correlation_id`], `None` for dead letters stored
+    /// before it was kept.
+    pub correlation_id: Option<Uuid>,
+    /// The message's headers, restored on replay like its correlation id,
+    /// partition key and priority.
+    pub headers: BTreeMap<String, String>,
+    pub partition_key: Option<String>,
+    pub priority: Priority,
+}
+
+impl DeadLetter {
+    pub(crate) fn new(msg: &Message, handler: Option<String>, error: String) -> Self {
+        let envelope = msg.envelope();
+        DeadLetter {
+            id: Uuid::now_v7(),
+            routing_key: msg.routing_key.clone(),
+            data: msg.data.clone(),
+            schema_version: envelope.schema_version(),
+            handler,
+            error,
+            attempts: envelope.attempt(),
+            failed_at: SystemTime::now(),
+            correlation_id: Some(envelope.correlation_id()),
+            headers: envelope.headers().clone(),
+            partition_key: envelope.partition_key().map(str::to_string),
+            priority: envelope.priority(),
+        }
+    }
+
+    /// Decodes the payload as the message of handler `T`, if it has the
+    /// handler's current schema version.
+    pub fn decode<T>(&self) -> Result<T::Msg, CodecError>
+    where
+        T: Route,
+    {
+        match self.schema_version == T::SCHEMA_VERSION {
+            true => <T::Msg as Payload>::Codec::decode(&self.data),
+            false => Err(CodecError::UnsupportedVersion(self.schema_version)),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum DeadLetterError {
+    #[error(transparent)]
+    Db(#[from] DbError),
+    #[error(transparent)]
+    Codec(#[from] CodecError),
+    #[error(transparent)]
+    Publish(#[from] PublishError),
+    #[error("dead letter {0} not found")]
+    NotFound(Uuid),
+}
+
+pub trait DeadLetterStore: Send + Sync + 'static {
+    fn insert(&self, dead_letter: DeadLetter) -> BoxFuture<'_, Result<(), DeadLetterError>>;
+
+    fn list(&self) -> BoxFuture<'_, Result<Vec<DeadLetter>, DeadLetterError>>;
+
+    fn get(&self, id: Uuid) -> BoxFuture<'_, Result<Option<DeadLetter>, DeadLetterError>>;
+
+    fn remove(&self, id: Uuid) -> BoxFuture<'_, Result<Option<DeadLetter>, DeadLetterError>>;
+}
+
+#[derive(Debug, Default)]
+pub struct InMemoryDeadLetterStore {
+    dead_letters: Mutex<BTreeMap<Uuid, DeadLetter>>,
+}
+
+impl DeadLetterStore for InMemoryDeadLetterStore {
+    fn insert(&self, dead_letter: DeadLetter) -> BoxFuture<'_, Result<(), DeadLetterError>> {
+        self.dead_letters
+            .lock()
+            .expect("poisoned")
+            .insert(dead_letter.id, dead_letter);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn list(&self) -> BoxFuture<'_, Result<Vec<DeadLetter>, DeadLetterError>> {
+        let dead_letters = self
+            .dead_letters
+            .lock()
+            .expect("poisoned")
+            .values()
+            .cloned()
+            .collect();
+        Box::pin(async { Ok(dead_letters) })
+    }
+
+    fn get(&self, id: Uuid) -> BoxFuture<'_, Result<Option<DeadLetter>, DeadLetterError>> {
+        let dead_letter = self
+            .dead_letters
+            .lock()
+            .expect("poisoned")
+            .get(&id)
+            .cloned();
+        Box::pin(async { Ok(dead_letter) })
+    }
+
+    fn remove(&self, id: Uuid) -> BoxFuture<'_, Result<Option<DeadLetter>, DeadLetterError>> {
+        let dead_letter = self.dead_letters.lock().expect("poisoned").remove(&id);
+        Box::pin(async { Ok(dead_letter) })
+    }
+}
+
+#[derive(Debug)]
+pub struct PostgresDeadLetterStore {
+    db_client: DbClient,
+}
+
+impl PostgresDeadLetterStore {
+    pub fn new(db_client: DbClient) -> Self {
+        PostgresDeadLetterStore { db_client }
+    }
+
+    pub async fn connect(db_config: &DbConfig) -> Result<Self, DeadLetterError> {
+        Ok(Self::new(DbClient::connect(db_config).await?))
+    }
+}
+
+impl DeadLetterStore for PostgresDeadLetterStore {
+    fn insert(&self, dead_letter: DeadLetter) -> BoxFuture<'_, Result<(), DeadLetterError>> {
+        Box::pin(async move { Ok(self.db_client.insert_dead_letter(dead_letter).await?) })
+    }
+
+    fn list(&self) -> BoxFuture<'_, Result<Vec<DeadLetter>, DeadLetterError>> {
+        Box::pin(async move { Ok(self.db_client.get_dead_letters().await?) })
+    }
+
+    fn get(&self, id: Uuid) -> BoxFuture<'_, Result<Option<DeadLetter>, DeadLetterError>> {
+        Box::pin(async move { Ok(self.db_client.get_dead_letter(id).await?) })
+    }
+
+    fn remove(&self, id: Uuid) -> BoxFuture<'_, Result<Option<DeadLetter>, DeadLetterError>> {
+        Box::pin(async move { Ok(self.db_client.delete_dead_letter(id).await?) })
+    }
+}
+
+/// Lists, inspects and replays the dead letters of a broker.
+#[derive(Clone)]
+pub struct DeadLetters {
+    store: Arc<dyn DeadLetterStore>,
+    publisher: Publisher,
+}
+
+impl DeadLetters {
+    pub(crate) fn new(store: Arc<dyn DeadLetterStore>, publisher: Publisher) -> Self {
+        DeadLetters { store, publisher }
+    }
+
+    pub async fn list(&self) -> Result<Vec<DeadLetter>, DeadLetterError> {
+        self.store.list().await
+    }
+
+    pub async fn get(&self, id: Uuid) -> Result<Option<DeadLetter>, DeadLetterError> {
+        self.store.get(id).await
+    }
+
+    /// Publishes the dead letter again, to the handler that failed only, and
+    /// removes it from the store.
+    pub async fn replay(&self, id: Uuid) -> Result<(), DeadLetterError> {
+        let dead_letter = self
+            .store
+            .get(id)
+            .await?
+            .ok_or(DeadLetterError::NotFound(id))?;
+        self.publisher.replay(dead_letter).await?;
+        self.store.remove(id).await?;
+        Ok(())
+    }
+
+    /// Like [`DeadLetters::replay`] but with a corrected message for handler `T`.
+    pub async fn replay_with<T>(&self, id: Uuid, msg: T::Msg) -> Result<(), DeadLetterError>
+    where
+        T: Route,
+    {
+        let mut dead_letter = self
+            .store
+            .get(id)
+            .await?
+            .ok_or(DeadLetterError::NotFound(id))?;
+        dead_letter.data = <T::Msg as Payload>::Codec::encode(&msg)?;
+        dead_letter.schema_version = T::SCHEMA_VERSION;
+        dead_letter.headers.insert(
+            CODEC_HEADER.to_string(),
+            <T::Msg as Payload>::Codec::NAME.to_string(),
+        );
+        self.publisher.replay(dead_letter).await?;
+        self.store.remove(id).await?;
+        Ok(())
+    }
+}
+
+impl From<DeadLetter> for db::entities::DeadLetter {
+    fn from(value: DeadLetter) -> Self {
+        db::entities::DeadLetter {
+            dead_letter_id: value.id,
+            routing_key: value.routing_key,
+            payload: value.data,
+            schema_version: value.schema_version.try_into().unwrap_or(i32::MAX),
+            error: value.error,
+            attempts: value.attempts.try_into().unwrap_or(i32::MAX),
+            failed_at: value.failed_at,
+            handler: value.handler,
+            correlation_id: value.correlation_id,
+            headers: Some(Json(value.headers)),
+            partition_key: value.partition_key,
+            priority: Some(value.priority.lane() as i16),
+        }
+    }
+}
+
+impl From<db::entities::DeadLetter> for DeadLetter {
+    fn from(value: db::entities::DeadLetter) -> Self {
+        DeadLetter {
+            id: value.dead_letter_id,
+            routing_key: value.routing_key,
+            data: value.payload,
+            schema_version: value.schema_version.try_into().unwrap_or(1),
+            handler: value.handler,
+            error: value.error,
+            attempts: value.attempts.try_into().unwrap_or_default(),
+            failed_at: value.failed_at,
+            correlation_id: value.correlation_id,
+            headers: value.headers.map(|headers| headers.0).unwrap_or_default(),
+            partition_key: value.partition_key,
+            priority: value
+                .priority
+                .and_then(|lane| usize::try_from(lane).ok())
+                .and_then(Priority::from_lane)
+                .unwrap_or_default(),
+        }
+    }
+}
@@ -0,0 +1,78 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+/// How long and how many idempotency keys a handler remembers, see
+/// [`crate::MessageHandler::with_dedup`].
+#[derive(Debug, Clone, Copy)]
+pub struct DedupWindow {
+    window: Duration,
+    capacity: usize,
+}
+
+impl DedupWindow {
+    /// Remembers keys for `window`, forgetting the oldest once more than
+    /// `capacity` are held.
+    pub fn new(window: Duration, capacity: usize) -> Self {
+        DedupWindow {
+            window,
+            capacity: capacity.max(1),
+        }
+    }
+}
+
+/// Idempotency keys seen within the window, oldest first.
+pub(crate) struct DedupCache {
+    config: DedupWindow,
+    seen: Mutex<Seen>,
+}
+
+#[derive(Default)]
+struct Seen {
+    keys: HashMap<String, Instant>,
+    order: VecDeque<(Instant, String)>,
+}
+
+impl DedupCache {
+    pub(crate) fn new(config: DedupWindow) -> Self {
+        DedupCache {
+            config,
+            seen: Mutex::new(Seen::default()),
+        }
+    }
+
+    /// Remembers `key`, returning whether it was not seen within the window.
+    pub(crate) fn insert(&self, key: String) -> bool {
+        let mut seen = self.seen.lock().expect("poisoned");
+        let now = Instant::now();
+        while let Some((at, _)) = seen.order.front() {
+            if now.duration_since(*at) <= self.config.window {
+                break;
+            }
+            let (_, key) = seen.order.pop_front().expect("front exists");
+            seen.keys.remove(&key);
+        }
+        if seen.keys.contains_key(&key) {
+            return false;
+        }
+        seen.keys.insert(key.clone(), now);
+        seen.order.push_back((now, key));
+        if seen.order.len() > self.config.capacity {
+            let (_, key) = seen.order.pop_front().expect("over capacity");
+            seen.keys.remove(&key);
+        }
+        true
+    }
+
+    /// Forgets `key`, so the next message with it is handled.
+    pub(crate) fn remove(&self, key: &str) {
+        let mut seen = self.seen.lock().expect("poisoned");
+        if seen.keys.remove(key).is_some() {
+            seen.order.retain(|(_, seen)| seen != key);
+        }
+    }
+}
@@ -0,0 +1,100 @@
+use std::{cmp::Ordering, collections::BinaryHeap, sync::Mutex};
+
+use tokio::{sync::Notify, time::Instant};
+
+/// Items held back until a deadline, earliest first. Items with the same
+/// deadline come out in the order they were pushed.
+pub(crate) struct DelayQueue<T> {
+    inner: Mutex<Inner<T>>,
+    pushed: Notify,
+}
+
+struct Inner<T> {
+    heap: BinaryHeap<Entry<T>>,
+    seq: u64,
+}
+
+impl<T> DelayQueue<T> {
+    pub(crate) fn new() -> Self {
+        DelayQueue {
+            inner: Mutex::new(Inner {
+                heap: BinaryHeap::new(),
+                seq: 0,
+            }),
+            pushed: Notify::new(),
+        }
+    }
+
+    pub(crate) fn push(&self, item: T, at: Instant) {
+        let mut inner = self.inner.lock().expect("poisoned");
+        let seq = inner.seq;
+        inner.seq += 1;
+        inner.heap.push(Entry { at, seq, item });
+        self.pushed.notify_one();
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.inner.lock().expect("poisoned").heap.is_empty()
+    }
+
+    /// The earliest item whose deadline has passed.
+    pub(crate) fn pop_due(&self) -> Option<T> {
+        let mut inner = self.inner.lock().expect("poisoned");
+        match inner.heap.peek() {
+            Some(entry) if entry.at <= Instant::now() => inner.heap.pop().map(|e| e.item),
+            _ => None,
+        }
+    }
+
+    /// Everything still held, earliest first.
+    pub(crate) fn take_all(&self) -> Vec<T> {
+        let mut heap = std::mem::take(&mut self.inner.lock().expect("poisoned").heap);
+        std::iter::from_fn(|| heap.pop().map(|e| e.item)).collect()
+    }
+
+    /// Waits until the earliest deadline passes or an item is pushed, either
+    /// of which may make an item due.
+    pub(crate) async fn wait(&self) {
+        let next = self
+            .inner
+            .lock()
+            .expect("poisoned")
+            .heap
+            .peek()
+            .map(|e| e.at);
+        match next {
+            Some(at) => tokio::select! {
+                _ = tokio::time::sleep_until(at) => {}
+                _ = self.pushed.notified() => {}
+            },
+            None => self.pushed.notified().await,
+        }
+    }
+}
+
+struct Entry<T> {
+    at: Instant,
+    seq: u64,
+    item: T,
+}
+
+impl<T> PartialEq for Entry<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T> Eq for Entry<T> {}
+
+impl<T> PartialOrd for Entry<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Entry<T> {
+    /// Reversed, the heap pops the earliest deadline first.
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.at, other.seq).cmp(&(self.at, self.seq))
+    }
+}
@@ -0,0 +1,156 @@
+use std::{
+    collections::BTreeMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bincode::{Decode, Encode};
+use uuid::Uuid;
+
+/// Header with the schema version of the payload, see
+/// [`crate::Handler::SCHEMA_VERSION`].
+pub const SCHEMA_VERSION_HEADER: &str = "schema-version";
+
+/// Header with the [`crate::Codec::NAME`] the payload was encoded with.
+pub const CODEC_HEADER: &str = "codec";
+
+/// Which lane a message waits in, see [`crate::SendOptions::priority`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Encode, Decode)]
+pub enum Priority {
+    /// Live traffic, served before anything else.
+    High,
+    #[default]
+    Normal,
+    /// Bulk work like backfills, served once nothing else is waiting.
+    Low,
+}
+
+impl Priority {
+    /// Every priority, highest first.
+    pub const ALL: [Priority; 3] = [Priority::High, Priority::Normal, Priority::Low];
+
+    /// Position in [`Priority::ALL`], lower is served first.
+    pub(crate) fn lane(self) -> usize {
+        self as usize
+    }
+
+    pub(crate) fn from_lane(lane: usize) -> Option<Self> {
+        Priority::ALL.get(lane).copied()
+    }
+}
+
+/// Metadata that travels with every message.
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct Envelope {
+    id: u128,
+    published_at: u64,
+    pub(crate) attempt: u32,
+    correlation_id: u128,
+    headers: BTreeMap<String, String>,
+    partition_key: Option<String>,
+    pub(crate) priority: Priority,
+    /// Unix millis after which the message is discarded instead of handled.
+    pub(crate) expires_at: Option<u64>,
+    /// Set by [`crate::Publisher::request`], the id is the request to answer.
+    pub(crate) reply: bool,
+    /// Names of the handlers still to see the message, all subscribed
+    /// handlers if empty.
+    pub(crate) handlers: Vec<String>,
+}
+
+impl Envelope {
+    pub(crate) fn new(
+        correlation_id: Option<Uuid>,
+        headers: BTreeMap<String, String>,
+        partition_key: Option<String>,
+    ) -> Self {
+        let id = Uuid::now_v7();
+        Envelope {
+            id: id.as_u128(),
+            published_at: to_unix_millis(SystemTime::now()),
+            attempt: 1,
+            correlation_id: correlation_id.unwrap_or(id).as_u128(),
+            headers,
+            partition_key,
+            priority: Priority::Normal,
+            expires_at: None,
+            reply: false,
+            handlers: Vec::new(),
+        }
+    }
+
+    /// Unique per published message, kept across retries.
+    pub fn id(&self) -> Uuid {
+        Uuid::from_u128(self.id)
+    }
+
+    pub fn published_at(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.published_at)
+    }
+
+    /// Starts at 1 and goes up with every retry.
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// Shared by messages that belong to the same flow, defaults to the id.
+    pub fn correlation_id(&self) -> Uuid {
+        Uuid::from_u128(self.correlation_id)
+    }
+
+    pub fn headers(&self) -> &BTreeMap<String, String> {
+        &self.headers
+    }
+
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.headers.get(key).map(String::as_str)
+    }
+
+    /// Version of the payload's schema, 1 for messages published before it
+    /// was recorded.
+    pub fn schema_version(&self) -> u32 {
+        self.header(SCHEMA_VERSION_HEADER)
+            .and_then(|version| version.parse().ok())
+            .unwrap_or(1)
+    }
+
+    /// Messages with the same partition key are handled one at a time, in
+    /// the order they were received.
+    pub fn partition_key(&self) -> Option<&str> {
+        self.partition_key.as_deref()
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    /// When the message stops being worth handling, see
+    /// [`crate::SendOptions::ttl`].
+    pub fn expires_at(&self) -> Option<SystemTime> {
+        self.expires_at
+            .map(|millis| UNIX_EPOCH + Duration::from_millis(millis))
+    }
+
+    pub(crate) fn expire_after(&mut self, ttl: Duration) {
+        let expires_at = self.published_at().checked_add(ttl);
+        self.expires_at = Some(expires_at.map_or(u64::MAX, to_unix_millis));
+    }
+
+    pub(crate) fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| to_unix_millis(SystemTime::now()) > expires_at)
+    }
+}
+
+impl Default for Envelope {
+    fn default() -> Self {
+        Self::new(None, BTreeMap::new(), None)
+    }
+}
+
+fn to_unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+        .try_into()
+        .unwrap_or(u64::MAX)
+}
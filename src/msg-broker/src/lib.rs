@@ -1,32 +1,136 @@
-use std::{fmt, sync::Arc};
+use std::{
+    collections::HashMap,
+    fmt,
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::{Arc, atomic},
+    time::Duration,
+};
 
-use bincode::{Decode, Encode, error::DecodeError};
-use handler_trait::InnerHandler;
-use tokio::sync::mpsc::{self, Receiver, Sender};
+pub use batch::{BatchHandler, BatchMessageHandler, Batched};
+use bincode::error::DecodeError;
+use breaker::Breakers;
+pub use breaker::{Admin, BreakerState, CircuitBreaker};
 
+pub use codec::{Bincode, Borsh, Codec, CodecError, Decoders, Json, Payload};
+pub use dead_letter::{
+    DeadLetter, DeadLetterError, DeadLetterStore, DeadLetters, InMemoryDeadLetterStore,
+    PostgresDeadLetterStore,
+};
+use dedup::DedupCache;
+pub use dedup::DedupWindow;
+pub use envelope::{CODEC_HEADER, Envelope, Priority, SCHEMA_VERSION_HEADER};
+pub use handler_trait::BoxFuture;
+use handler_trait::{HandlerWrapper, InnerHandler, RequestWrapper};
+pub use middleware::{HandlerTimeout, Middleware, Next, Request, Timeout, Trace};
+pub use panic::HandlerPanic;
+use panic::{catch_unwind, catch_unwind_sync};
+pub use publisher::{Backpressure, PublishError, Publisher, SendOptions};
+pub use rate_limit::RateLimit;
+use rate_limit::TokenBucket;
+use request::Replies;
+pub use request::{RequestError, RequestHandler};
+pub use retry::RetryPolicy;
+pub use shutdown::ShutdownToken;
+pub use socket::{Endpoint, RemoteTransport, SocketTransport};
+use stats::Counters;
+pub use stats::{BrokerStats, Histogram, RouteStats};
+use tokio::{
+    sync::{Mutex, Semaphore, mpsc, watch},
+    task::JoinSet,
+    time::Instant,
+};
+use topic::Pattern;
+pub use topic::Unrouted;
+pub use transport::{
+    Delivery, InMemoryTransport, PostgresTransport, Receipt, Transport, TransportError,
+};
+
+mod batch;
+mod breaker;
+mod codec;
+mod dead_letter;
+mod dedup;
+mod delay;
+mod envelope;
+mod middleware;
+mod panic;
+mod publisher;
+mod rate_limit;
+mod request;
+mod retry;
+mod shutdown;
+mod socket;
+mod stats;
+pub mod testing;
+mod topic;
+mod transport;
+mod wire;
+
+/// How long a message waits on the queue when its routing key is at its
+/// concurrency limit.
+const BUSY_DELAY: Duration = Duration::from_millis(25);
+
+/// How many received messages a partition lane holds before the workers
+/// feeding it wait for room.
+const LANE_CAPACITY: usize = 8;
+
+/// Why a handler failed. Handlers return [`HandlerError::Fatal`] or
+/// [`HandlerError::Transient`], the other variants are raised by the broker.
 #[derive(Debug)]
-pub struct HandlerError<E> {
-    pub inner_error: E,
-    pub error_kind: ErrorKind,
+pub enum HandlerError<E> {
+    Fatal(E),
+    Transient(E),
+    /// The payload did not decode, or a reply did not encode. Fatal.
+    Codec(CodecError),
+    /// The handler panicked. Fatal.
+    Panic(HandlerPanic),
+    /// The handler ran past its [`Timeout`]. Transient.
+    Timeout(HandlerTimeout),
 }
 
 impl<E> HandlerError<E> {
     pub fn fatal(err: E) -> Self {
-        HandlerError {
-            inner_error: err,
-            error_kind: ErrorKind::Fatal,
-        }
+        HandlerError::Fatal(err)
     }
 
     pub fn transient(err: E) -> Self {
-        HandlerError {
-            inner_error: err,
-            error_kind: ErrorKind::Transient,
+        HandlerError::Transient(err)
+    }
+
+    pub fn error_kind(&self) -> ErrorKind {
+        match self {
+            HandlerError::Transient(_) | HandlerError::Timeout(_) => ErrorKind::Transient,
+            HandlerError::Fatal(_) | HandlerError::Codec(_) | HandlerError::Panic(_) => {
+                ErrorKind::Fatal
+            }
+        }
+    }
+
+    /// The handler's own error, `None` for errors raised by the broker.
+    pub fn inner_error(&self) -> Option<&E> {
+        match self {
+            HandlerError::Fatal(err) | HandlerError::Transient(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl<E> HandlerError<E>
+where
+    E: fmt::Debug,
+{
+    /// What dead letters and logs say about the error.
+    fn describe(&self) -> String {
+        match self {
+            HandlerError::Fatal(err) | HandlerError::Transient(err) => format!("{:?}", err),
+            HandlerError::Codec(err) => err.to_string(),
+            HandlerError::Panic(panic) => panic.to_string(),
+            HandlerError::Timeout(timeout) => timeout.to_string(),
         }
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ErrorKind {
     Transient,
     Fatal,
@@ -37,20 +141,82 @@ mod handler_trait {
 
     use bincode::error::DecodeError;
 
-    use crate::{ErrorKind, Handler, HandlerError};
+    use crate::{
+        BatchHandler, Codec, CodecError, Envelope, Handler, HandlerError, Message, Payload,
+        RequestHandler, codec::Decoding, request::Replies,
+    };
+
+    pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+    /// One entry per message of a batch, `None` where the handler returned no
+    /// result.
+    pub type BatchResults<Err> = Vec<Option<Result<(), HandlerError<Err>>>>;
 
     pub trait InnerHandler: Send + Sync + 'static {
         type Context;
         type Error;
 
+        /// Replies, if the handler has any, go to `replies`.
         fn handle<'a>(
             &'a self,
             ctx: Arc<Self::Context>,
-            msg: Vec<u8>,
-        ) -> Pin<Box<dyn Future<Output = Result<(), HandlerError<Self::Error>>> + Send + 'a>>;
+            msg: &[u8],
+            envelope: Envelope,
+            replies: &'a Replies,
+        ) -> BoxFuture<'a, Result<(), HandlerError<Self::Error>>>;
+
+        /// See [`Handler::idempotency_key`], `None` if `msg` does not decode.
+        fn idempotency_key(&self, _msg: &[u8], _envelope: &Envelope) -> Option<String> {
+            None
+        }
+    }
+
+    pub trait InnerBatchHandler: Send + Sync + 'static {
+        type Context;
+        type Error;
+
+        /// Returns the results in the order of `msgs`.
+        fn handle_batch<'a>(
+            &'a self,
+            ctx: Arc<Self::Context>,
+            msgs: Vec<&Message>,
+        ) -> BoxFuture<'a, BatchResults<Self::Error>>;
+    }
+
+    /// `bincode` errors go to the handler's error type like they always did,
+    /// errors of other codecs are reported by the broker.
+    fn codec_error<Err>(err: CodecError) -> HandlerError<Err>
+    where
+        Err: From<DecodeError>,
+    {
+        match err {
+            CodecError::BincodeDecode(err) => HandlerError::Fatal(err.into()),
+            err => HandlerError::Codec(err),
+        }
     }
 
-    impl<T, Ctx, Err> InnerHandler for T
+    /// A [`Handler`] along with the decoders for its message.
+    pub struct HandlerWrapper<T>
+    where
+        T: Handler,
+    {
+        handler: T,
+        decoding: Decoding<T::Msg>,
+    }
+
+    impl<T> HandlerWrapper<T>
+    where
+        T: Handler,
+    {
+        pub fn new(handler: T) -> Self {
+            HandlerWrapper {
+                handler,
+                decoding: Decoding::new(T::SCHEMA_VERSION, T::decoders()),
+            }
+        }
+    }
+
+    impl<T, Ctx, Err> InnerHandler for HandlerWrapper<T>
     where
         T: Handler<Context = Ctx, Error = Err>,
         Ctx: Send + Sync + 'static,
@@ -62,21 +228,143 @@ mod handler_trait {
         fn handle<'a>(
             &'a self,
             ctx: Arc<Ctx>,
-            msg: Vec<u8>,
-        ) -> Pin<Box<dyn Future<Output = Result<(), HandlerError<Self::Error>>> + Send + 'a>>
-        {
-            let msg = match bincode::decode_from_slice(&msg, bincode::config::standard()) {
-                Ok(msg) => msg.0,
-                Err(err) => {
-                    return Box::pin(async {
-                        Err(HandlerError {
-                            inner_error: err.into(),
-                            error_kind: ErrorKind::Fatal,
-                        })
-                    });
-                }
+            msg: &[u8],
+            envelope: Envelope,
+            _replies: &'a Replies,
+        ) -> BoxFuture<'a, Result<(), HandlerError<Self::Error>>> {
+            let msg = match self.decoding.decode(&envelope, msg) {
+                Ok(msg) => msg,
+                Err(err) => return Box::pin(async { Err(codec_error(err)) }),
+            };
+            Box::pin(async move {
+                self.handler
+                    .handle_envelope(ctx, msg, envelope)
+                    .await
+                    .map_err(Into::into)
+            })
+        }
+
+        fn idempotency_key(&self, msg: &[u8], envelope: &Envelope) -> Option<String> {
+            let msg = self.decoding.decode(envelope, msg).ok()?;
+            self.handler.idempotency_key(&msg, envelope)
+        }
+    }
+
+    /// A [`RequestHandler`] along with the decoders for its message.
+    pub struct RequestWrapper<T>
+    where
+        T: RequestHandler,
+    {
+        handler: T,
+        decoding: Decoding<T::Msg>,
+    }
+
+    impl<T> RequestWrapper<T>
+    where
+        T: RequestHandler,
+    {
+        pub fn new(handler: T) -> Self {
+            RequestWrapper {
+                handler,
+                decoding: Decoding::new(T::SCHEMA_VERSION, T::decoders()),
+            }
+        }
+    }
+
+    impl<T, Ctx, Err> InnerHandler for RequestWrapper<T>
+    where
+        T: RequestHandler<Context = Ctx, Error = Err>,
+        Ctx: Send + Sync + 'static,
+        Err: From<DecodeError> + Into<HandlerError<Err>> + Send + Sync + 'static,
+    {
+        type Context = Ctx;
+        type Error = Err;
+
+        fn handle<'a>(
+            &'a self,
+            ctx: Arc<Ctx>,
+            msg: &[u8],
+            envelope: Envelope,
+            replies: &'a Replies,
+        ) -> BoxFuture<'a, Result<(), HandlerError<Self::Error>>> {
+            let msg = match self.decoding.decode(&envelope, msg) {
+                Ok(msg) => msg,
+                Err(err) => return Box::pin(async { Err(codec_error(err)) }),
             };
-            Box::pin(async move { T::handle(&self, ctx, msg).await.map_err(Into::into) })
+            Box::pin(async move {
+                let reply = self.handler.handle(ctx, msg).await.map_err(Into::into)?;
+                if envelope.reply {
+                    let reply = <T::Reply as Payload>::Codec::encode(&reply)
+                        .map_err(HandlerError::Codec)?;
+                    replies.reply(envelope.id(), Ok(reply));
+                }
+                Ok(())
+            })
+        }
+    }
+
+    /// A [`BatchHandler`] along with the decoders for its message.
+    pub struct BatchWrapper<T>
+    where
+        T: BatchHandler,
+    {
+        handler: T,
+        decoding: Decoding<T::Msg>,
+    }
+
+    impl<T> BatchWrapper<T>
+    where
+        T: BatchHandler,
+    {
+        pub fn new(handler: T) -> Self {
+            BatchWrapper {
+                handler,
+                decoding: Decoding::new(T::SCHEMA_VERSION, T::decoders()),
+            }
+        }
+    }
+
+    impl<T, Ctx, Err> InnerBatchHandler for BatchWrapper<T>
+    where
+        T: BatchHandler<Context = Ctx, Error = Err>,
+        Ctx: Send + Sync + 'static,
+        Err: From<DecodeError> + Into<HandlerError<Err>> + Send + Sync + 'static,
+    {
+        type Context = Ctx;
+        type Error = Err;
+
+        fn handle_batch<'a>(
+            &'a self,
+            ctx: Arc<Ctx>,
+            msgs: Vec<&Message>,
+        ) -> BoxFuture<'a, BatchResults<Self::Error>> {
+            // `None` marks the messages handed to the handler, decode errors
+            // are reported in place.
+            let mut results = Vec::with_capacity(msgs.len());
+            let mut decoded = Vec::with_capacity(msgs.len());
+            for msg in msgs {
+                match self.decoding.decode(&msg.envelope, &msg.data) {
+                    Ok(msg) => {
+                        results.push(None);
+                        decoded.push(msg);
+                    }
+                    Err(err) => results.push(Some(Err(codec_error(err)))),
+                }
+            }
+            Box::pin(async move {
+                let mut handled = match decoded.is_empty() {
+                    true => Vec::new(),
+                    false => self.handler.handle_batch(ctx, decoded).await,
+                }
+                .into_iter();
+                results
+                    .into_iter()
+                    .map(|res| match res {
+                        Some(res) => Some(res),
+                        None => handled.next().map(|res| res.map_err(Into::into)),
+                    })
+                    .collect()
+            })
         }
     }
 }
@@ -84,15 +372,63 @@ mod handler_trait {
 pub trait Handler: Send + Sync + 'static {
     type Context;
     type Error: From<DecodeError> + Into<HandlerError<Self::Error>>;
-    type Msg: Encode + Decode<()> + Send + Sync;
+    /// Encoded with [`Bincode`] unless it implements [`Payload`] itself.
+    type Msg: Payload + Send + Sync;
 
     const ROUTING_KEY: &str;
 
+    /// Bump it whenever `Msg` changes shape, and register a decoder for the
+    /// old version in [`Handler::decoders`] so queued messages keep working.
+    const SCHEMA_VERSION: u32 = 1;
+
     fn handle(
         &self,
         ctx: Arc<Self::Context>,
         msg: Self::Msg,
     ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Called by the broker instead of [`Handler::handle`]. Override it to
+    /// read the message's [`Envelope`].
+    fn handle_envelope(
+        &self,
+        ctx: Arc<Self::Context>,
+        msg: Self::Msg,
+        _envelope: Envelope,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        self.handle(ctx, msg)
+    }
+
+    /// Identifies messages that mean the same thing, like two log lines for
+    /// one transaction. Only used by handlers registered
+    /// [`MessageHandler::with_dedup`]. Panicking here fails the message like
+    /// a panic in the handler.
+    fn idempotency_key(&self, _msg: &Self::Msg, _envelope: &Envelope) -> Option<String> {
+        None
+    }
+
+    /// Decoders for messages published with an older
+    /// [`Handler::SCHEMA_VERSION`].
+    fn decoders() -> Decoders<Self::Msg> {
+        Decoders::new()
+    }
+}
+
+/// Where messages for a handler are published, see [`Publisher::send`].
+pub trait Route {
+    type Msg: Payload;
+
+    const ROUTING_KEY: &str;
+    const SCHEMA_VERSION: u32 = 1;
+}
+
+impl<T> Route for T
+where
+    T: Handler,
+{
+    type Msg = T::Msg;
+
+    const ROUTING_KEY: &str = T::ROUTING_KEY;
+    const SCHEMA_VERSION: u32 = T::SCHEMA_VERSION;
 }
 
 pub struct MessageHandler<Ctx, Err>
@@ -101,7 +437,13 @@ where
     Err: Send + Sync + 'static,
 {
     routing_key: &'static str,
+    name: &'static str,
+    patterns: Vec<Pattern>,
     handler: Arc<dyn InnerHandler<Context = Ctx, Error = Err>>,
+    retry_policy: RetryPolicy,
+    rate_limit: Option<TokenBucket>,
+    dedup: Option<DedupCache>,
+    middleware: Vec<Arc<dyn Middleware<Ctx, Err>>>,
 }
 
 impl<Ctx, Err> MessageHandler<Ctx, Err>
@@ -115,91 +457,2576 @@ where
     {
         MessageHandler {
             routing_key: T::ROUTING_KEY,
-            handler: Arc::new(handler),
+            name: std::any::type_name::<T>(),
+            patterns: vec![Pattern::new(T::ROUTING_KEY)],
+            handler: Arc::new(HandlerWrapper::new(handler)),
+            retry_policy: RetryPolicy::default(),
+            rate_limit: None,
+            dedup: None,
+            middleware: Vec::new(),
+        }
+    }
+
+    /// Registers a handler that answers [`Publisher::request`]s.
+    pub fn request<T>(handler: T) -> Self
+    where
+        T: RequestHandler<Context = Ctx, Error = Err>,
+    {
+        MessageHandler {
+            routing_key: T::ROUTING_KEY,
+            name: std::any::type_name::<T>(),
+            patterns: vec![Pattern::new(T::ROUTING_KEY)],
+            handler: Arc::new(RequestWrapper::new(handler)),
+            retry_policy: RetryPolicy::default(),
+            rate_limit: None,
+            dedup: None,
+            middleware: Vec::new(),
         }
     }
+
+    /// Overrides how transient errors from this handler are retried.
+    pub fn with_retry(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Paces the messages this handler sees. A message that finds no token
+    /// reserves the next free one and goes back on the queue until then,
+    /// without holding a worker. A message with a partition key waits in its
+    /// lane instead.
+    pub fn with_rate_limit(mut self, rate_limit: RateLimit) -> Self {
+        self.rate_limit = Some(TokenBucket::new(rate_limit));
+        self
+    }
+
+    /// Drops messages whose [`Handler::idempotency_key`] was seen within
+    /// `window` before they reach the handler. Retries are never dropped, and
+    /// a key is forgotten when its message fails for good.
+    pub fn with_dedup(mut self, window: DedupWindow) -> Self {
+        self.dedup = Some(DedupCache::new(window));
+        self
+    }
+
+    /// Also delivers messages whose routing key matches `pattern`, like
+    /// `swap.*` or `swap.#`. Every handler subscribed to a routing key sees
+    /// the message.
+    pub fn subscribe(mut self, pattern: &str) -> Self {
+        self.patterns.push(Pattern::new(pattern));
+        self
+    }
+
+    /// Identifies the handler in retried messages and dead letters, defaults
+    /// to the handler's type name. Must be unique within a broker.
+    pub fn with_name(mut self, name: &'static str) -> Self {
+        self.name = name;
+        self
+    }
 }
 
+#[derive(Debug, Clone)]
 pub struct Message {
     routing_key: String,
     data: Vec<u8>,
+    envelope: Envelope,
 }
 
-pub struct Publisher {
-    tx: Sender<Message>,
+impl Message {
+    pub fn routing_key(&self) -> &str {
+        &self.routing_key
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn envelope(&self) -> &Envelope {
+        &self.envelope
+    }
 }
 
-impl Publisher {
-    pub async fn send<T>(&self, msg: T::Msg)
-    where
-        T: Handler,
-    {
-        self.tx
-            .send(Message {
-                routing_key: T::ROUTING_KEY.to_string(),
-                data: bincode::encode_to_vec(msg, bincode::config::standard()).expect("test"),
-            })
-            .await
-            .expect("test")
+pub struct MessageBrokerBuilder<Ctx, Err>
+where
+    Ctx: Send + Sync + 'static,
+    Err: Send + Sync + 'static,
+{
+    context: Ctx,
+    handlers: Vec<MessageHandler<Ctx, Err>>,
+    batch_handlers: Vec<BatchMessageHandler<Ctx, Err>>,
+    workers: usize,
+    partitions: Option<usize>,
+    concurrency_limits: HashMap<String, usize>,
+    circuit_breakers: HashMap<String, CircuitBreaker>,
+    dead_letters: Arc<dyn DeadLetterStore>,
+    transport: Arc<dyn Transport>,
+    replies: Arc<Replies>,
+    counters: Arc<Counters>,
+    middleware: Vec<Arc<dyn Middleware<Ctx, Err>>>,
+    route_middleware: HashMap<String, Vec<Arc<dyn Middleware<Ctx, Err>>>>,
+    unrouted: Unrouted,
+    shutdown: ShutdownToken,
+    drain_timeout: Duration,
+}
+
+impl<Ctx, Err> MessageBrokerBuilder<Ctx, Err>
+where
+    Ctx: Sync + Send + 'static,
+    Err: fmt::Debug + Sync + Send + 'static,
+{
+    pub fn handler(mut self, handler: MessageHandler<Ctx, Err>) -> Self {
+        self.handlers.push(handler);
+        self
+    }
+
+    pub fn handlers(mut self, handlers: Vec<MessageHandler<Ctx, Err>>) -> Self {
+        self.handlers.extend(handlers);
+        self
+    }
+
+    pub fn batch_handler(mut self, handler: BatchMessageHandler<Ctx, Err>) -> Self {
+        self.batch_handlers.push(handler);
+        self
+    }
+
+    /// Number of workers pulling from the queue. This is also the global
+    /// limit on how many messages are handled at once.
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.workers = workers.max(1);
+        self
+    }
+
+    /// Number of serial lanes for messages published with a partition key, see
+    /// [`SendOptions::partition_key`]. Defaults to the number of workers.
+    ///
+    /// Each lane holds up to 8 received messages, a worker that receives one
+    /// more waits for room before receiving again. A lane also waits out its
+    /// routing keys' breakers, pauses, concurrency limits and rate limits in
+    /// place. Messages in a lane are already leased, so everything queued in
+    /// it must be handled well within the transport's lease, 60 seconds by
+    /// default on a [`PostgresTransport`].
+    pub fn partitions(mut self, partitions: usize) -> Self {
+        self.partitions = Some(partitions.max(1));
+        self
+    }
+
+    /// Caps how many messages for `routing_key` are handled at once. Messages
+    /// over the cap go back on the queue for a moment instead of holding up a
+    /// worker, those with a partition key wait in their lane.
+    pub fn concurrency_limit(mut self, routing_key: &str, limit: usize) -> Self {
+        self.concurrency_limits
+            .insert(routing_key.to_string(), limit.max(1));
+        self
+    }
+
+    /// Stops dispatching `routing_key` while its handlers keep failing, see
+    /// [`CircuitBreaker`].
+    pub fn circuit_breaker(mut self, routing_key: &str, breaker: CircuitBreaker) -> Self {
+        self.circuit_breakers
+            .insert(routing_key.to_string(), breaker);
+        self
+    }
+
+    /// Where messages that failed with a fatal error are kept. Defaults to an
+    /// [`InMemoryDeadLetterStore`].
+    pub fn dead_letter_store(mut self, store: impl DeadLetterStore) -> Self {
+        self.dead_letters = Arc::new(store);
+        self
+    }
+
+    /// The queue messages go through. Defaults to an [`InMemoryTransport`].
+    pub fn transport(mut self, transport: impl Transport) -> Self {
+        self.transport = Arc::new(transport);
+        self
+    }
+
+    /// Runs on the transport of `publisher` and shares its stats and
+    /// replies, for a context that publishes through the broker, like a
+    /// handler sending follow-up messages. Those publishes then show in
+    /// [`MessageBroker::stats`].
+    pub fn publisher(mut self, publisher: &Publisher) -> Self {
+        self.transport = publisher.transport.clone();
+        self.replies = publisher.replies.clone();
+        self.counters = publisher.counters.clone();
+        self
+    }
+
+    /// What to do with messages no handler is subscribed to. Defaults to
+    /// [`Unrouted::DeadLetter`].
+    pub fn unrouted(mut self, unrouted: Unrouted) -> Self {
+        self.unrouted = unrouted;
+        self
+    }
+
+    /// Stops the broker once `shutdown` fires. The transport is closed, then
+    /// messages in flight and still queued are handled until the drain
+    /// timeout runs out.
+    pub fn shutdown(mut self, shutdown: ShutdownToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// How long a shutdown waits for the queue to drain, 30 seconds by
+    /// default. Handlers still running after that are dropped, and their
+    /// messages dead lettered along with everything still queued or waiting
+    /// to be retried.
+    pub fn drain_timeout(mut self, drain_timeout: Duration) -> Self {
+        self.drain_timeout = drain_timeout;
+        self
+    }
+
+    /// Wraps every handler. Middleware runs in the order it was added, global
+    /// middleware before middleware for a routing key.
+    pub fn middleware(mut self, middleware: impl Middleware<Ctx, Err>) -> Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    /// Wraps the handler for `routing_key`.
+    pub fn route_middleware(
+        mut self,
+        routing_key: &str,
+        middleware: impl Middleware<Ctx, Err>,
+    ) -> Self {
+        self.route_middleware
+            .entry(routing_key.to_string())
+            .or_default()
+            .push(Arc::new(middleware));
+        self
+    }
+
+    pub fn build(mut self) -> MessageBroker<Ctx, Err> {
+        for handler in self.handlers.iter_mut() {
+            handler.middleware = self
+                .middleware
+                .iter()
+                .chain(
+                    self.route_middleware
+                        .get(handler.routing_key)
+                        .into_iter()
+                        .flatten(),
+                )
+                .cloned()
+                .collect();
+        }
+        let concurrency_limits = self
+            .concurrency_limits
+            .into_iter()
+            .map(|(key, limit)| (key, Semaphore::new(limit)))
+            .collect();
+
+        MessageBroker {
+            workers: self.workers,
+            partitions: self.partitions.unwrap_or(self.workers),
+            shutdown: self.shutdown,
+            drain_timeout: self.drain_timeout,
+            shared: Arc::new(Shared {
+                transport: self.transport,
+                context: Arc::new(self.context),
+                handlers: self.handlers,
+                batch_handlers: self.batch_handlers,
+                concurrency_limits,
+                breakers: Arc::new(Breakers::new(self.circuit_breakers)),
+                dead_letters: self.dead_letters,
+                unrouted: self.unrouted,
+                recv_lock: Mutex::new(()),
+                pending: watch::Sender::new(0),
+                replies: self.replies,
+                counters: self.counters,
+            }),
+        }
     }
 }
 
-pub struct MessageBroker<Ctx, Err>
+struct Shared<Ctx, Err>
 where
     Ctx: Send + Sync + 'static,
     Err: Send + Sync + 'static,
 {
-    tx: Sender<Message>,
-    rx: Receiver<Message>,
+    transport: Arc<dyn Transport>,
     context: Arc<Ctx>,
     handlers: Vec<MessageHandler<Ctx, Err>>,
+    batch_handlers: Vec<BatchMessageHandler<Ctx, Err>>,
+    concurrency_limits: HashMap<String, Semaphore>,
+    breakers: Arc<Breakers>,
+    dead_letters: Arc<dyn DeadLetterStore>,
+    unrouted: Unrouted,
+    /// Held from receiving a delivery until it is in its partition lane.
+    recv_lock: Mutex<()>,
+    /// Deliveries received and not yet acked or requeued, which a draining
+    /// broker keeps receiving for, since their retries are still to come.
+    pending: watch::Sender<usize>,
+    replies: Arc<Replies>,
+    counters: Arc<Counters>,
 }
 
-impl<Ctx, Err> MessageBroker<Ctx, Err>
+impl<Ctx, Err> Shared<Ctx, Err>
 where
     Ctx: Sync + Send + 'static,
     Err: fmt::Debug + Sync + Send + 'static,
 {
-    pub fn new(ctx: Ctx, handlers: Vec<MessageHandler<Ctx, Err>>) -> Self {
-        let (tx, rx) = mpsc::channel(12);
-        MessageBroker {
-            tx,
-            rx,
-            context: Arc::new(ctx),
-            handlers,
+    /// Dispatches a delivery received by [`MessageBroker::run`], unless the
+    /// drain `deadline` passes first, in which case it is dead lettered.
+    async fn dispatch_until(
+        &self,
+        delivery: Delivery,
+        batchers: &Batchers,
+        admission: Admission,
+        deadline: &ShutdownToken,
+    ) {
+        let (message, receipt) = (delivery.message.clone(), delivery.receipt);
+        let batched = tokio::select! {
+            biased;
+            _ = deadline.wait() => {
+                self.abandon(message, Some(receipt)).await;
+                false
+            }
+            handled = self.dispatch(delivery, batchers, admission) => handled.is_none(),
+        };
+        // The batcher settles its own.
+        if !batched {
+            self.settled(1);
         }
     }
 
-    pub fn get_publisher(&self) -> Publisher {
-        Publisher {
-            tx: self.tx.clone(),
+    /// Returns what each handler the message was handed to returned, nothing
+    /// when it was parked or unrouted, and `None` when it went to a batcher.
+    async fn dispatch(
+        &self,
+        delivery: Delivery,
+        batchers: &Batchers,
+        admission: Admission,
+    ) -> Option<Handled<Err>> {
+        self.counters.dequeued(&delivery.message.routing_key);
+        if delivery.message.envelope.is_expired() {
+            self.expired(delivery).await;
+            return Some(Vec::new());
+        }
+        while let Some(delay) = self.breakers.admit(&delivery.message.routing_key) {
+            match admission {
+                Admission::Park => {
+                    self.park(delivery, delay).await;
+                    return Some(Vec::new());
+                }
+                Admission::Wait => tokio::time::sleep(delay).await,
+            }
+        }
+        if let Some(batcher) = batchers.get(delivery.message.routing_key.as_str()) {
+            let Err(mpsc::error::SendError(delivery)) = batcher.send(delivery).await else {
+                return None;
+            };
+            println!(
+                "WARN: batcher for {} stopped, requeueing",
+                delivery.message.routing_key
+            );
+            self.requeue(delivery.receipt, delivery.message, Duration::ZERO)
+                .await;
+            return Some(Vec::new());
+        }
+        let msg = &delivery.message;
+        let handlers = self
+            .handlers
+            .iter()
+            .filter(|h| match msg.envelope.handlers.is_empty() {
+                true => h.patterns.iter().any(|p| p.matches(&msg.routing_key)),
+                false => msg.envelope.handlers.iter().any(|name| name == h.name),
+            })
+            .collect::<Vec<_>>();
+        if handlers.is_empty() {
+            self.unrouted(delivery).await;
+            return Some(Vec::new());
+        }
+        let limit = self.concurrency_limits.get(&msg.routing_key);
+        let _permit = match limit {
+            Some(limit) => match limit.try_acquire() {
+                Ok(permit) => Some(permit),
+                Err(_) if admission == Admission::Wait => limit.acquire().await.ok(),
+                Err(_) => {
+                    self.park(delivery, BUSY_DELAY).await;
+                    return Some(Vec::new());
+                }
+            },
+            None => None,
+        };
+        let id = msg.envelope.id();
+        let rate_limits = handlers.iter().filter_map(|h| h.rate_limit.as_ref());
+        let wait = rate_limits.clone().map(|bucket| bucket.reserve(id)).max();
+        match (wait.filter(|wait| !wait.is_zero()), admission) {
+            (Some(wait), Admission::Park) => {
+                self.park(delivery, wait).await;
+                return Some(Vec::new());
+            }
+            (Some(wait), Admission::Wait) => tokio::time::sleep(wait).await,
+            (None, _) => {}
         }
+        rate_limits.for_each(|bucket| bucket.settle(id));
+        Some(self.handle(handlers, delivery).await)
     }
 
-    pub async fn run(mut self) {
-        // TODO refactor to use recv_many
-        // TODO create a pool of workers
-        // TODO reply on transient error / record signatures for fatal errors
-        while let Some(msg) = self.rx.recv().await {
-            match self
-                .handlers
-                .iter()
-                .find(|h| h.routing_key == msg.routing_key)
-            {
-                Some(handler) => {
-                    let res = handler.handler.handle(self.context.clone(), msg.data).await;
-                    if res.is_err() {
-                        if !self.rx.is_closed() {
-                            self.rx.close();
-                        }
-                        println!("WARN: handler err for {}", msg.routing_key);
+    /// Runs every handler subscribed to the message. Handlers that fail with a
+    /// transient error are retried together, and only they see the message
+    /// again.
+    async fn handle(
+        &self,
+        handlers: Vec<&MessageHandler<Ctx, Err>>,
+        delivery: Delivery,
+    ) -> Handled<Err> {
+        let Delivery {
+            message: mut msg,
+            receipt,
+        } = delivery;
+        let attempt = msg.envelope.attempt();
+        let mut retry = Vec::new();
+        let mut delay = Duration::ZERO;
+        let mut failed = false;
+        let mut handled = Vec::with_capacity(handlers.len());
+        let mut invoked = false;
+        for handler in handlers {
+            let key = match &handler.dedup {
+                Some(_) => {
+                    catch_unwind_sync(|| handler.handler.idempotency_key(&msg.data, &msg.envelope))
+                }
+                None => Ok(None),
+            };
+            let duplicate = match (&handler.dedup, &key, attempt) {
+                (Some(dedup), Ok(Some(key)), 1) => !dedup.insert(key.to_string()),
+                _ => false,
+            };
+            if duplicate {
+                self.counters
+                    .route(&msg.routing_key, |stats| stats.deduplicated += 1);
+                handled.push((handler.name, Ok(())));
+                continue;
+            }
+            invoked = true;
+            let req = Request {
+                routing_key: &msg.routing_key,
+                context: self.context.clone(),
+                data: &msg.data,
+                envelope: &msg.envelope,
+            };
+            let next = Next::new(&handler.middleware, handler.handler.as_ref(), &self.replies);
+            let started = Instant::now();
+            let res = match &key {
+                Ok(_) => catch_unwind(Box::pin(async move { next.run(req).await })).await,
+                Err(panic) => Err(panic.clone()),
+            };
+            let res = match res {
+                Ok(res) => res,
+                Err(panic) => Err(HandlerError::Panic(self.panicked(handler.name, panic))),
+            };
+            self.counters.route(&msg.routing_key, |stats| {
+                stats.latency.observe(started.elapsed());
+                match &res {
+                    Ok(()) => stats.handled += 1,
+                    Err(err) if err.error_kind() == ErrorKind::Transient => {
+                        stats.failed_transient += 1
                     }
+                    Err(_) => stats.failed_fatal += 1,
+                }
+            });
+            let Err(err) = &res else {
+                handled.push((handler.name, res));
+                continue;
+            };
+            let error = err.describe();
+            let retry_policy = &handler.retry_policy;
+            failed |= err.error_kind() == ErrorKind::Transient;
+            let retrying =
+                err.error_kind() == ErrorKind::Transient && retry_policy.should_retry(attempt);
+            if !retrying
+                && let Some(dedup) = &handler.dedup
+                && let Ok(Some(key)) = &key
+            {
+                dedup.remove(key);
+            }
+            if retrying {
+                let backoff = retry_policy.backoff(attempt);
+                retry.push(handler.name.to_string());
+                delay = delay.max(backoff);
+            } else if !self
+                .store_dead_letter(&msg, Some(handler.name), error)
+                .await
+            {
+                retry.push(handler.name.to_string());
+                delay = delay.max(retry_policy.backoff(attempt));
+            }
+            handled.push((handler.name, res));
+        }
+        // Duplicates say nothing about the health of the key.
+        if invoked {
+            self.breakers.record(&msg.routing_key, failed);
+        }
+        match retry.is_empty() {
+            true => self.ack(receipt).await,
+            false => {
+                self.counters
+                    .route(&msg.routing_key, |stats| stats.retried += 1);
+                msg.envelope.handlers = retry;
+                self.requeue(receipt, msg, delay).await;
+            }
+        }
+        handled
+    }
+
+    async fn expired(&self, delivery: Delivery) {
+        let Delivery {
+            message: msg,
+            receipt,
+        } = delivery;
+        self.counters
+            .route(&msg.routing_key, |stats| stats.expired += 1);
+        self.ack(receipt).await;
+        if msg.envelope.reply {
+            let error = "ttl ran out before dispatch".to_string();
+            self.replies.reply(msg.envelope.id(), Err(error));
+        }
+    }
+
+    async fn unrouted(&self, delivery: Delivery) {
+        let Delivery {
+            message: msg,
+            receipt,
+        } = delivery;
+        match self.unrouted {
+            Unrouted::DeadLetter => {
+                let error = format!("no handler for {}", msg.routing_key);
+                if self.store_dead_letter(&msg, None, error).await {
+                    self.ack(receipt).await;
                 }
+            }
+            Unrouted::Drop => {
+                println!("WARN: no handler for {}, dropping", msg.routing_key);
+                self.ack(receipt).await;
+            }
+            Unrouted::Close => {
+                println!("WARN: no handler for {}, closing", msg.routing_key);
+                self.transport.close();
+                self.ack(receipt).await;
+            }
+        }
+    }
+
+    /// Handles a batch of messages for one batch handler. Returns what it
+    /// returned for each message, `None` where it returned nothing.
+    async fn dispatch_batch(
+        &self,
+        handler: &BatchMessageHandler<Ctx, Err>,
+        batch: Vec<Delivery>,
+    ) -> Vec<Option<Result<(), HandlerError<Err>>>> {
+        let msgs = batch.iter().map(|d| &d.message).collect();
+        let handle_batch = async {
+            handler
+                .handler
+                .handle_batch(self.context.clone(), msgs)
+                .await
+        };
+        let started = Instant::now();
+        let results = match catch_unwind(Box::pin(handle_batch)).await {
+            Ok(results) => results,
+            Err(panic) => {
+                let err = self.panicked(handler.name, panic);
+                let failed = || Some(Err(HandlerError::Panic(err.clone())));
+                std::iter::repeat_with(failed).take(batch.len()).collect()
+            }
+        };
+        self.counters.route(handler.routing_key, |stats| {
+            stats.latency.observe(started.elapsed())
+        });
+        let mut results = results.into_iter();
+        let mut settled = Vec::with_capacity(batch.len());
+        for Delivery { message, receipt } in batch {
+            match results.next().flatten() {
+                Some(res) => settled.push(Some(self.settle(handler, receipt, message, res).await)),
                 None => {
-                    println!("WARN: no handler for {}", msg.routing_key);
-                    self.rx.close();
+                    let error = "batch handler returned no result".to_string();
+                    self.fail(handler, receipt, message, ErrorKind::Transient, error)
+                        .await;
+                    settled.push(None);
                 }
-            };
+            }
+        }
+        settled
+    }
+
+    async fn settle(
+        &self,
+        handler: &BatchMessageHandler<Ctx, Err>,
+        receipt: Receipt,
+        msg: Message,
+        res: Result<(), HandlerError<Err>>,
+    ) -> Result<(), HandlerError<Err>> {
+        match &res {
+            Ok(()) => {
+                self.counters
+                    .route(&msg.routing_key, |stats| stats.handled += 1);
+                self.breakers.record(&msg.routing_key, false);
+                self.ack(receipt).await
+            }
+            Err(err) => {
+                let error = err.describe();
+                self.fail(handler, receipt, msg, err.error_kind(), error)
+                    .await
+            }
+        }
+        res
+    }
+
+    /// Retries transient failures while the policy allows it, dead letters
+    /// everything else.
+    async fn fail(
+        &self,
+        handler: &BatchMessageHandler<Ctx, Err>,
+        receipt: Receipt,
+        msg: Message,
+        error_kind: ErrorKind,
+        error: String,
+    ) {
+        let attempt = msg.envelope.attempt();
+        let retry_policy = &handler.retry_policy;
+        self.breakers
+            .record(&msg.routing_key, error_kind == ErrorKind::Transient);
+        self.counters
+            .route(&msg.routing_key, |stats| match error_kind {
+                ErrorKind::Transient => stats.failed_transient += 1,
+                ErrorKind::Fatal => stats.failed_fatal += 1,
+            });
+        if error_kind == ErrorKind::Transient && retry_policy.should_retry(attempt) {
+            let delay = retry_policy.backoff(attempt);
+            self.counters
+                .route(&msg.routing_key, |stats| stats.retried += 1);
+            self.requeue(receipt, msg, delay).await;
+            return;
         }
+        if self
+            .store_dead_letter(&msg, Some(handler.name), error)
+            .await
+        {
+            self.ack(receipt).await;
+        }
+    }
+
+    fn panicked(&self, handler: &str, panic: HandlerPanic) -> HandlerPanic {
+        self.counters.panics.fetch_add(1, atomic::Ordering::Relaxed);
+        println!("WARN: {} in {}", panic, handler);
+        panic
+    }
+
+    async fn ack(&self, receipt: Receipt) {
+        if let Err(err) = self.transport.ack(receipt).await {
+            println!("WARN: failed to ack message: {}", err);
+        }
+    }
+
+    /// Returns whether the dead letter was stored, the message must not be
+    /// acked otherwise.
+    async fn store_dead_letter(&self, msg: &Message, handler: Option<&str>, error: String) -> bool {
+        println!(
+            "WARN: handler err for {} ({}) after {} attempt(s): {}",
+            msg.routing_key,
+            msg.envelope.id(),
+            msg.envelope.attempt(),
+            error
+        );
+        let reply = msg.envelope.reply.then(|| error.clone());
+        let dead_letter = DeadLetter::new(msg, handler.map(str::to_string), error);
+        match self.dead_letters.insert(dead_letter).await {
+            Ok(()) => {
+                if let Some(error) = reply {
+                    self.replies.reply(msg.envelope.id(), Err(error));
+                }
+                true
+            }
+            Err(err) => {
+                println!("WARN: failed to store dead letter: {}", err);
+                false
+            }
+        }
+    }
+
+    /// Puts a message back without counting an attempt, for messages that
+    /// cannot be dispatched yet, like those of a paused routing key.
+    async fn park(&self, delivery: Delivery, delay: Duration) {
+        let Delivery { message, receipt } = delivery;
+        let routing_key = message.routing_key.clone();
+        match self.transport.requeue(receipt, message, delay).await {
+            Ok(()) => self.counters.queued(&routing_key),
+            Err(err) => println!("WARN: failed to park message: {}", err),
+        }
+    }
+
+    /// Dead letters a message the broker gave up on when its drain timed out.
+    async fn abandon(&self, msg: Message, receipt: Option<Receipt>) {
+        let error = "broker shut down before it was handled".to_string();
+        if self.store_dead_letter(&msg, None, error).await
+            && let Some(receipt) = receipt
+        {
+            self.ack(receipt).await;
+        }
+    }
+
+    fn received(&self) {
+        self.pending.send_modify(|pending| *pending += 1);
+    }
+
+    fn settled(&self, count: usize) {
+        self.pending.send_modify(|pending| *pending -= count);
+    }
+
+    /// Waits until a pending delivery is acked or requeued, `false` if none
+    /// are pending.
+    async fn settling(&self) -> bool {
+        let mut pending = self.pending.subscribe();
+        if *pending.borrow_and_update() == 0 {
+            return false;
+        }
+        pending.changed().await.is_ok()
+    }
+
+    async fn requeue(&self, receipt: Receipt, mut msg: Message, delay: Duration) {
+        msg.envelope.attempt += 1;
+        let routing_key = msg.routing_key.clone();
+        match self.transport.requeue(receipt, msg, delay).await {
+            Ok(()) => self.counters.queued(&routing_key),
+            Err(err) => println!("WARN: failed to requeue message: {}", err),
+        }
+    }
+
+    /// Collects deliveries for the batch handler at `index` until the batch
+    /// is full or has lingered long enough, then handles them together.
+    async fn run_batcher(
+        &self,
+        index: usize,
+        mut rx: mpsc::Receiver<Delivery>,
+        deadline: ShutdownToken,
+    ) {
+        let handler = &self.batch_handlers[index];
+        let mut batch = Vec::with_capacity(handler.max_batch_size);
+        while rx.recv_many(&mut batch, handler.max_batch_size).await > 0 {
+            let lingered = tokio::time::Instant::now() + handler.linger;
+            while batch.len() < handler.max_batch_size {
+                let limit = handler.max_batch_size - batch.len();
+                match tokio::time::timeout_at(lingered, rx.recv_many(&mut batch, limit)).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => {}
+                }
+            }
+            let batch = std::mem::take(&mut batch);
+            let count = batch.len();
+            let copies = (batch.iter())
+                .map(|d| (d.message.clone(), d.receipt))
+                .collect::<Vec<_>>();
+            tokio::select! {
+                biased;
+                _ = deadline.wait() => {
+                    for (message, receipt) in copies {
+                        self.abandon(message, Some(receipt)).await;
+                    }
+                }
+                _ = self.dispatch_batch(handler, batch) => {}
+            }
+            self.settled(count);
+        }
+    }
+}
+
+/// What each handler returned for a message, by handler name.
+type Handled<Err> = Vec<(&'static str, Result<(), HandlerError<Err>>)>;
+
+/// Senders into the batcher of each batch handler, by routing key.
+type Batchers = HashMap<&'static str, mpsc::Sender<Delivery>>;
+
+/// What to do with a message that cannot be dispatched yet, because its
+/// routing key is paused, at its concurrency limit or out of rate limit
+/// tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Admission {
+    /// Put it back on the queue, so the worker can move on.
+    Park,
+    /// Wait for it in place, holding up the partition lane it is in so the
+    /// lane keeps its order.
+    Wait,
+}
+
+/// The serial lane of one or more partition keys. Deliveries are queued in
+/// the order they were received and dispatched one at a time.
+struct Lane {
+    tx: mpsc::UnboundedSender<Delivery>,
+    /// Deliveries queued and not yet taken up for dispatch.
+    queued: Arc<watch::Sender<usize>>,
+}
+
+impl Lane {
+    fn new() -> (Self, mpsc::UnboundedReceiver<Delivery>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let queued = Arc::new(watch::Sender::new(0));
+        (Lane { tx, queued }, rx)
+    }
+
+    /// Queues `delivery` behind everything received before it, or hands it
+    /// back if the lane stopped.
+    fn push(&self, delivery: Delivery) -> Option<Delivery> {
+        self.queued.send_modify(|queued| *queued += 1);
+        let err = self.tx.send(delivery).err()?;
+        self.queued.send_modify(|queued| *queued -= 1);
+        Some(err.0)
+    }
+
+    /// Waits until the lane holds no more than [`LANE_CAPACITY`] deliveries.
+    async fn room(&self) {
+        let mut queued = self.queued.subscribe();
+        let _ = queued.wait_for(|queued| *queued <= LANE_CAPACITY).await;
+    }
+}
+
+/// The lane for partition `key`.
+fn partition(key: &str, partitions: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    (hasher.finish() % partitions as u64) as usize
+}
+
+pub struct MessageBroker<Ctx, Err>
+where
+    Ctx: Send + Sync + 'static,
+    Err: Send + Sync + 'static,
+{
+    workers: usize,
+    partitions: usize,
+    shutdown: ShutdownToken,
+    drain_timeout: Duration,
+    shared: Arc<Shared<Ctx, Err>>,
+}
+
+impl<Ctx, Err> MessageBroker<Ctx, Err>
+where
+    Ctx: Sync + Send + 'static,
+    Err: fmt::Debug + Sync + Send + 'static,
+{
+    pub fn new(ctx: Ctx, handlers: Vec<MessageHandler<Ctx, Err>>) -> Self {
+        Self::builder(ctx).handlers(handlers).build()
+    }
+
+    pub fn builder(ctx: Ctx) -> MessageBrokerBuilder<Ctx, Err> {
+        MessageBrokerBuilder {
+            context: ctx,
+            handlers: Vec::new(),
+            batch_handlers: Vec::new(),
+            workers: 1,
+            partitions: None,
+            concurrency_limits: HashMap::new(),
+            circuit_breakers: HashMap::new(),
+            dead_letters: Arc::new(InMemoryDeadLetterStore::default()),
+            transport: Arc::new(InMemoryTransport::default()),
+            replies: Arc::new(Replies::default()),
+            counters: Arc::new(Counters::default()),
+            middleware: Vec::new(),
+            route_middleware: HashMap::new(),
+            unrouted: Unrouted::default(),
+            shutdown: ShutdownToken::new(),
+            drain_timeout: Duration::from_secs(30),
+        }
+    }
+
+    pub fn get_publisher(&self) -> Publisher {
+        Publisher::new(self.shared.transport.clone())
+            .with_replies(self.shared.replies.clone())
+            .with_counters(self.shared.counters.clone())
+    }
+
+    pub fn stats(&self) -> BrokerStats {
+        BrokerStats::new(self.shared.counters.clone())
+    }
+
+    /// Pauses and resumes routing keys while the broker runs.
+    pub fn admin(&self) -> Admin {
+        Admin::new(self.shared.breakers.clone())
+    }
+
+    pub fn dead_letters(&self) -> DeadLetters {
+        DeadLetters::new(self.shared.dead_letters.clone(), self.get_publisher())
+    }
+
+    /// Runs until the transport is closed and drained, or until the drain
+    /// timeout runs out after a shutdown.
+    pub async fn run(self) {
+        // Fires once the drain times out, everything still held is then dead
+        // lettered instead of handled.
+        let deadline = ShutdownToken::new();
+        let mut workers = JoinSet::new();
+        let mut batchers = Batchers::new();
+        for (index, handler) in self.shared.batch_handlers.iter().enumerate() {
+            let (tx, rx) = mpsc::channel(handler.max_batch_size);
+            batchers.insert(handler.routing_key, tx);
+            let shared = self.shared.clone();
+            let deadline = deadline.clone();
+            workers.spawn(async move { shared.run_batcher(index, rx, deadline).await });
+        }
+        // Batchers and lanes stop once everything sending to them is done.
+        let batchers = Arc::new(batchers);
+        let mut partitions = Vec::with_capacity(self.partitions);
+        for _ in 0..self.partitions {
+            let (lane, mut rx) = Lane::new();
+            let queued = lane.queued.clone();
+            partitions.push(lane);
+            let shared = self.shared.clone();
+            let batchers = batchers.clone();
+            let deadline = deadline.clone();
+            workers.spawn(async move {
+                while let Some(delivery) = rx.recv().await {
+                    queued.send_modify(|queued| *queued -= 1);
+                    shared
+                        .dispatch_until(delivery, &batchers, Admission::Wait, &deadline)
+                        .await;
+                }
+            });
+        }
+        let partitions = Arc::new(partitions);
+        for _ in 0..self.workers {
+            let shared = self.shared.clone();
+            let batchers = batchers.clone();
+            let partitions = partitions.clone();
+            let deadline = deadline.clone();
+            workers.spawn(async move {
+                loop {
+                    let recv = tokio::select! {
+                        biased;
+                        _ = deadline.wait() => break,
+                        recv = shared.recv_lock.lock() => recv,
+                    };
+                    let received = tokio::select! {
+                        biased;
+                        _ = deadline.wait() => break,
+                        received = shared.transport.recv() => received,
+                    };
+                    let delivery = match received {
+                        Ok(Some(delivery)) => delivery,
+                        Ok(None) => {
+                            drop(recv);
+                            // Lanes and batchers may still requeue retries.
+                            let settled = tokio::select! {
+                                biased;
+                                _ = deadline.wait() => false,
+                                settled = shared.settling() => settled,
+                            };
+                            match settled {
+                                true => continue,
+                                false => break,
+                            }
+                        }
+                        Err(err) => {
+                            drop(recv);
+                            println!("WARN: failed to receive message: {}", err);
+                            tokio::time::sleep(Duration::from_secs(1)).await;
+                            continue;
+                        }
+                    };
+                    shared.received();
+                    let Some(key) = delivery.message.envelope.partition_key() else {
+                        drop(recv);
+                        shared
+                            .dispatch_until(delivery, &batchers, Admission::Park, &deadline)
+                            .await;
+                        continue;
+                    };
+                    // Queued before the next receive, so the lane sees the
+                    // key's messages in order. Waiting for room comes after.
+                    let lane = &partitions[partition(key, partitions.len())];
+                    let stopped = lane.push(delivery);
+                    drop(recv);
+                    match stopped {
+                        None => tokio::select! {
+                            biased;
+                            _ = deadline.wait() => {}
+                            _ = lane.room() => {}
+                        },
+                        Some(delivery) => {
+                            println!("WARN: partition lane stopped, requeueing");
+                            shared
+                                .requeue(delivery.receipt, delivery.message, Duration::ZERO)
+                                .await;
+                            shared.settled(1);
+                        }
+                    }
+                }
+            });
+        }
+        drop(batchers);
+        drop(partitions);
+
+        let transport = self.shared.transport.clone();
+        let drain = async {
+            self.shutdown.wait().await;
+            println!(
+                "WARN: shutting down, draining for up to {:?}",
+                self.drain_timeout
+            );
+            transport.close();
+            tokio::time::sleep(self.drain_timeout).await;
+        };
+        let drained = tokio::select! {
+            _ = async { while workers.join_next().await.is_some() {} } => true,
+            _ = drain => false,
+        };
+        if drained {
+            return;
+        }
+        println!("WARN: drain timed out, dead lettering what is left");
+        deadline.shutdown();
+        while workers.join_next().await.is_some() {}
+        for msg in transport.take_remaining().await {
+            self.shared.abandon(msg, None).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        sync::{
+            Arc,
+            atomic::{AtomicU32, Ordering},
+        },
+        time::Duration,
+    };
+
+    use bincode::error::DecodeError;
+    use serde::{Deserialize, Serialize};
+    use tokio::sync::{Barrier, mpsc};
+
+    use crate::{
+        Backpressure, BatchHandler, BatchMessageHandler, Batched, BoxFuture, BreakerState,
+        BrokerStats, CODEC_HEADER, CircuitBreaker, CodecError, DeadLetter, DeadLetters, Decoders,
+        DedupWindow, Envelope, Handler, HandlerError, InMemoryTransport, Json, LANE_CAPACITY,
+        Message, MessageBroker, MessageHandler, Middleware, Next, Payload, Priority, PublishError,
+        Publisher, RateLimit, Request, RequestError, RequestHandler, RetryPolicy,
+        SCHEMA_VERSION_HEADER, SendOptions, ShutdownToken, Timeout, TokenBucket, Transport,
+        TransportError, Unrouted, partition,
+        stats::Counters,
+        testing::{FakePublisher, Step, TestBroker},
+        topic::Pattern,
+        wire,
+    };
+
+    #[derive(Debug)]
+    enum TestError {
+        Transient,
+        Fatal,
+    }
+
+    impl From<DecodeError> for TestError {
+        fn from(_: DecodeError) -> Self {
+            TestError::Fatal
+        }
+    }
+
+    impl From<TestError> for HandlerError<TestError> {
+        fn from(value: TestError) -> Self {
+            match value {
+                TestError::Transient => HandlerError::transient(value),
+                TestError::Fatal => HandlerError::fatal(value),
+            }
+        }
+    }
+
+    struct TestContext {
+        barrier: Barrier,
+        done: mpsc::UnboundedSender<u32>,
+    }
+
+    struct Rendezvous;
+
+    impl Handler for Rendezvous {
+        type Context = TestContext;
+        type Error = TestError;
+        type Msg = u32;
+
+        const ROUTING_KEY: &str = "rendezvous";
+
+        async fn handle(&self, ctx: Arc<TestContext>, msg: u32) -> Result<(), TestError> {
+            ctx.barrier.wait().await;
+            ctx.done.send(msg).map_err(|_| TestError::Fatal)
+        }
+    }
+
+    struct FlakyContext {
+        attempts: AtomicU32,
+        done: mpsc::UnboundedSender<u32>,
+    }
+
+    /// Fails with a transient error until the message's attempt count is reached.
+    struct Flaky;
+
+    impl Handler for Flaky {
+        type Context = FlakyContext;
+        type Error = TestError;
+        type Msg = u32;
+
+        const ROUTING_KEY: &str = "flaky";
+
+        async fn handle(&self, ctx: Arc<FlakyContext>, msg: u32) -> Result<(), TestError> {
+            let attempt = ctx.attempts.fetch_add(1, Ordering::SeqCst) + 1;
+            if attempt < msg {
+                return Err(TestError::Transient);
+            }
+            ctx.done.send(attempt).map_err(|_| TestError::Fatal)
+        }
+    }
+
+    /// Meets [`Rendezvous`] at the barrier from a routing key of its own,
+    /// reports its message plus 100.
+    struct Meet;
+
+    impl Handler for Meet {
+        type Context = TestContext;
+        type Error = TestError;
+        type Msg = u32;
+
+        const ROUTING_KEY: &str = "meet";
+
+        async fn handle(&self, ctx: Arc<TestContext>, msg: u32) -> Result<(), TestError> {
+            ctx.barrier.wait().await;
+            ctx.done.send(100 + msg).map_err(|_| TestError::Fatal)
+        }
+    }
+
+    /// Reports every message it sees plus 100.
+    struct Echo;
+
+    impl Handler for Echo {
+        type Context = FlakyContext;
+        type Error = TestError;
+        type Msg = u32;
+
+        const ROUTING_KEY: &str = "echo";
+
+        async fn handle(&self, ctx: Arc<FlakyContext>, msg: u32) -> Result<(), TestError> {
+            ctx.done.send(100 + msg).map_err(|_| TestError::Fatal)
+        }
+    }
+
+    /// Panics on zero, reports anything else.
+    struct Panicky;
+
+    impl Handler for Panicky {
+        type Context = mpsc::UnboundedSender<u32>;
+        type Error = TestError;
+        type Msg = u32;
+
+        const ROUTING_KEY: &str = "panicky";
+
+        async fn handle(&self, ctx: Arc<Self::Context>, msg: u32) -> Result<(), TestError> {
+            assert_ne!(msg, 0, "unknown discriminator");
+            ctx.send(msg).map_err(|_| TestError::Fatal)
+        }
+    }
+
+    /// Reports its message keyed on itself, fails zero for good and 100 and
+    /// up transiently. Panics computing the key of 99.
+    struct Idempotent;
+
+    impl Handler for Idempotent {
+        type Context = mpsc::UnboundedSender<u32>;
+        type Error = TestError;
+        type Msg = u32;
+
+        const ROUTING_KEY: &str = "idempotent";
+
+        async fn handle(&self, ctx: Arc<Self::Context>, msg: u32) -> Result<(), TestError> {
+            match msg {
+                0 => return Err(TestError::Fatal),
+                100.. => return Err(TestError::Transient),
+                _ => {}
+            }
+            ctx.send(msg).map_err(|_| TestError::Fatal)
+        }
+
+        fn idempotency_key(&self, msg: &u32, _envelope: &Envelope) -> Option<String> {
+            assert_ne!(*msg, 99, "no key");
+            Some(msg.to_string())
+        }
+    }
+
+    /// Reports its message after sleeping longer the smaller the message is.
+    struct Sleepy;
+
+    impl Handler for Sleepy {
+        type Context = mpsc::UnboundedSender<u32>;
+        type Error = TestError;
+        type Msg = u32;
+
+        const ROUTING_KEY: &str = "sleepy";
+
+        async fn handle(&self, ctx: Arc<Self::Context>, msg: u32) -> Result<(), TestError> {
+            tokio::time::sleep(Duration::from_millis(10u64.saturating_sub(msg.into()))).await;
+            ctx.send(msg).map_err(|_| TestError::Fatal)
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct SwapV1 {
+        signature: String,
+    }
+
+    impl Payload for SwapV1 {
+        type Codec = Json;
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Swap {
+        signature: String,
+        slot: Option<u64>,
+    }
+
+    impl Payload for Swap {
+        type Codec = Json;
+    }
+
+    /// Publishes swaps the way an older deploy did.
+    struct OldSwaps;
+
+    impl crate::Route for OldSwaps {
+        type Msg = SwapV1;
+
+        const ROUTING_KEY: &str = "swap";
+    }
+
+    struct Swaps;
+
+    impl Handler for Swaps {
+        type Context = mpsc::UnboundedSender<(String, Option<u64>)>;
+        type Error = TestError;
+        type Msg = Swap;
+
+        const ROUTING_KEY: &str = "swap";
+        const SCHEMA_VERSION: u32 = 2;
+
+        async fn handle(&self, ctx: Arc<Self::Context>, msg: Swap) -> Result<(), TestError> {
+            ctx.send((msg.signature, msg.slot))
+                .map_err(|_| TestError::Fatal)
+        }
+
+        fn decoders() -> Decoders<Swap> {
+            Decoders::new().register::<SwapV1>(1, |old| Swap {
+                signature: old.signature,
+                slot: None,
+            })
+        }
+    }
+
+    /// Published to without a handler of its own.
+    struct SwapObserved;
+
+    impl crate::Route for SwapObserved {
+        type Msg = u32;
+
+        const ROUTING_KEY: &str = "swap.raydium";
+    }
+
+    /// Records every envelope and fails the first attempt.
+    struct Inspect;
+
+    impl Handler for Inspect {
+        type Context = mpsc::UnboundedSender<Envelope>;
+        type Error = TestError;
+        type Msg = u32;
+
+        const ROUTING_KEY: &str = "inspect";
+
+        async fn handle(&self, _ctx: Arc<Self::Context>, _msg: u32) -> Result<(), TestError> {
+            Ok(())
+        }
+
+        async fn handle_envelope(
+            &self,
+            ctx: Arc<Self::Context>,
+            _msg: u32,
+            envelope: Envelope,
+        ) -> Result<(), TestError> {
+            let attempt = envelope.attempt();
+            ctx.send(envelope).map_err(|_| TestError::Fatal)?;
+            match attempt {
+                1 => Err(TestError::Transient),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    /// Reports every batch and fails the messages that are zero.
+    struct Batch;
+
+    impl BatchHandler for Batch {
+        type Context = mpsc::UnboundedSender<Vec<u32>>;
+        type Error = TestError;
+        type Msg = u32;
+
+        const ROUTING_KEY: &str = "batch";
+
+        async fn handle_batch(
+            &self,
+            ctx: Arc<Self::Context>,
+            msgs: Vec<u32>,
+        ) -> Vec<Result<(), TestError>> {
+            let _ = ctx.send(msgs.clone());
+            msgs.into_iter()
+                .map(|msg| match msg {
+                    0 => Err(TestError::Fatal),
+                    _ => Ok(()),
+                })
+                .collect()
+        }
+    }
+
+    /// Never returns a result.
+    struct Forgetful;
+
+    impl BatchHandler for Forgetful {
+        type Context = ();
+        type Error = TestError;
+        type Msg = u32;
+
+        const ROUTING_KEY: &str = "forgetful";
+
+        async fn handle_batch(&self, _ctx: Arc<()>, _msgs: Vec<u32>) -> Vec<Result<(), TestError>> {
+            Vec::new()
+        }
+    }
+
+    /// Publishes to [`Forgetful`] with a schema version it cannot decode.
+    struct FutureForgetful;
+
+    impl crate::Route for FutureForgetful {
+        type Msg = u32;
+
+        const ROUTING_KEY: &str = "forgetful";
+        const SCHEMA_VERSION: u32 = 9;
+    }
+
+    /// Reports its label to the handler's `done` channel before passing the
+    /// message on.
+    struct Label(u32);
+
+    impl Middleware<TestContext, TestError> for Label {
+        fn handle<'a>(
+            &'a self,
+            req: Request<'a, TestContext>,
+            next: Next<'a, TestContext, TestError>,
+        ) -> BoxFuture<'a, Result<(), HandlerError<TestError>>> {
+            Box::pin(async move {
+                req.context
+                    .done
+                    .send(self.0)
+                    .map_err(|_| HandlerError::fatal(TestError::Fatal))?;
+                next.run(req).await
+            })
+        }
+    }
+
+    struct Double;
+
+    impl RequestHandler for Double {
+        type Context = TestContext;
+        type Error = TestError;
+        type Msg = u32;
+        type Reply = u64;
+
+        const ROUTING_KEY: &str = "double";
+
+        async fn handle(&self, _ctx: Arc<TestContext>, msg: u32) -> Result<u64, TestError> {
+            match msg {
+                0 => Err(TestError::Fatal),
+                msg => Ok(msg as u64 * 2),
+            }
+        }
+    }
+
+    fn flaky_broker(
+        retry_policy: RetryPolicy,
+    ) -> (
+        MessageBroker<FlakyContext, TestError>,
+        mpsc::UnboundedReceiver<u32>,
+    ) {
+        let (ctx, done_rx) = flaky_context();
+        let broker = MessageBroker::builder(ctx)
+            .handler(MessageHandler::new(Flaky).with_retry(retry_policy))
+            .build();
+        (broker, done_rx)
+    }
+
+    fn flaky_context() -> (FlakyContext, mpsc::UnboundedReceiver<u32>) {
+        let (done, done_rx) = mpsc::unbounded_channel();
+        let ctx = FlakyContext {
+            attempts: AtomicU32::new(0),
+            done,
+        };
+        (ctx, done_rx)
+    }
+
+    fn context(parties: usize) -> (TestContext, mpsc::UnboundedReceiver<u32>) {
+        let (done, done_rx) = mpsc::unbounded_channel();
+        let ctx = TestContext {
+            barrier: Barrier::new(parties),
+            done,
+        };
+        (ctx, done_rx)
+    }
+
+    async fn wait_for_dead_letter(dead_letters: &DeadLetters) -> DeadLetter {
+        tokio::time::timeout(Duration::from_secs(1), async {
+            loop {
+                if let Some(dead_letter) = dead_letters.list().await.unwrap().pop() {
+                    break dead_letter;
+                }
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("message was not dead lettered")
+    }
+
+    #[tokio::test]
+    async fn test_workers_handle_concurrently() {
+        let (ctx, mut done) = context(2);
+        let broker = MessageBroker::builder(ctx)
+            .handler(MessageHandler::new(Rendezvous))
+            .workers(2)
+            .build();
+        let publisher = broker.get_publisher();
+        tokio::spawn(broker.run());
+
+        publisher.send::<Rendezvous>(1).await.unwrap();
+        publisher.send::<Rendezvous>(2).await.unwrap();
+
+        let mut handled = Vec::new();
+        for _ in 0..2 {
+            let msg = tokio::time::timeout(Duration::from_secs(1), done.recv())
+                .await
+                .expect("handlers did not run concurrently");
+            handled.extend(msg);
+        }
+        handled.sort();
+        assert_eq!(handled, vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_limit_per_routing_key() {
+        let (ctx, mut done) = context(2);
+        let broker = MessageBroker::builder(ctx)
+            .handler(MessageHandler::new(Rendezvous))
+            .workers(2)
+            .concurrency_limit(Rendezvous::ROUTING_KEY, 1)
+            .build();
+        let publisher = broker.get_publisher();
+        tokio::spawn(broker.run());
+
+        publisher.send::<Rendezvous>(1).await.unwrap();
+        publisher.send::<Rendezvous>(2).await.unwrap();
+
+        let res = tokio::time::timeout(Duration::from_millis(100), done.recv()).await;
+        assert!(res.is_err(), "limit of one should serialize the handlers");
+    }
+
+    #[tokio::test]
+    async fn test_saturated_key_does_not_block_others() {
+        let (ctx, mut done) = context(2);
+        let broker = MessageBroker::builder(ctx)
+            .handler(MessageHandler::new(Rendezvous))
+            .handler(MessageHandler::new(Meet))
+            .workers(2)
+            .concurrency_limit(Rendezvous::ROUTING_KEY, 1)
+            .build();
+        let publisher = broker.get_publisher();
+        tokio::spawn(broker.run());
+
+        for msg in 1..=3 {
+            publisher.send::<Rendezvous>(msg).await.unwrap();
+        }
+        publisher.send::<Meet>(1).await.unwrap();
+
+        let mut handled = Vec::new();
+        for _ in 0..2 {
+            let msg = tokio::time::timeout(Duration::from_secs(1), done.recv())
+                .await
+                .expect("saturated key blocked the other");
+            handled.extend(msg);
+        }
+        handled.sort();
+        assert_eq!(handled, vec![1, 101]);
+    }
+
+    #[tokio::test]
+    async fn test_transient_errors_are_retried() {
+        let (broker, mut done) =
+            flaky_broker(RetryPolicy::new(3).base_delay(Duration::from_millis(1)));
+        let publisher = broker.get_publisher();
+        tokio::spawn(broker.run());
+
+        publisher.send::<Flaky>(3).await.unwrap();
+
+        let attempt = tokio::time::timeout(Duration::from_secs(1), done.recv())
+            .await
+            .expect("message was not retried");
+        assert_eq!(attempt, Some(3));
+    }
+
+    #[tokio::test]
+    async fn test_retries_stop_at_max_attempts() {
+        let (broker, mut done) =
+            flaky_broker(RetryPolicy::new(2).base_delay(Duration::from_millis(1)));
+        let publisher = broker.get_publisher();
+        tokio::spawn(broker.run());
+
+        publisher.send::<Flaky>(3).await.unwrap();
+
+        let res = tokio::time::timeout(Duration::from_millis(100), done.recv()).await;
+        assert!(
+            !matches!(res, Ok(Some(_))),
+            "third attempt should never run"
+        );
+    }
+
+    #[test]
+    fn test_backoff_is_capped() {
+        let policy = RetryPolicy::new(10)
+            .base_delay(Duration::from_millis(100))
+            .max_delay(Duration::from_secs(1));
+
+        let first = policy.backoff(1);
+        assert!(first >= Duration::from_millis(50) && first <= Duration::from_millis(100));
+
+        let last = policy.backoff(9);
+        assert!(last >= Duration::from_millis(500) && last <= Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_exhausted_messages_are_dead_lettered_and_replayed() {
+        let (broker, mut done) = flaky_broker(RetryPolicy::none());
+        let publisher = broker.get_publisher();
+        let dead_letters = broker.dead_letters();
+        tokio::spawn(broker.run());
+
+        publisher.send::<Flaky>(2).await.unwrap();
+
+        let dead_letter = wait_for_dead_letter(&dead_letters).await;
+        assert_eq!(dead_letter.routing_key, Flaky::ROUTING_KEY);
+        assert_eq!(dead_letter.attempts, 1);
+        assert_eq!(dead_letter.error, "Transient");
+        assert_eq!(dead_letter.decode::<Flaky>().unwrap(), 2);
+
+        dead_letters.replay(dead_letter.id).await.unwrap();
+
+        let attempt = tokio::time::timeout(Duration::from_secs(1), done.recv())
+            .await
+            .expect("replayed message was not handled");
+        assert_eq!(attempt, Some(2));
+        assert!(dead_letters.list().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_backpressure_when_queue_is_full() {
+        let (ctx, _done) = context(1);
+        let broker = MessageBroker::builder(ctx)
+            .handler(MessageHandler::new(Rendezvous))
+            .transport(InMemoryTransport::new(1))
+            .build();
+        let publisher = broker.get_publisher();
+        publisher.send::<Rendezvous>(1).await.unwrap();
+
+        let res = publisher.try_send::<Rendezvous>(2).await;
+        assert!(matches!(res, Err(PublishError::Full)));
+
+        let fail_fast = publisher.clone().with_backpressure(Backpressure::FailFast);
+        let res = fail_fast.send::<Rendezvous>(2).await;
+        assert!(matches!(res, Err(PublishError::Full)));
+
+        let drop = publisher.clone().with_backpressure(Backpressure::Drop);
+        drop.send::<Rendezvous>(2).await.unwrap();
+        drop.send::<Rendezvous>(3).await.unwrap();
+        assert_eq!(publisher.dropped(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_handlers_receive_envelope() {
+        let (ctx, mut envelopes) = mpsc::unbounded_channel();
+        let broker = MessageBroker::builder(ctx)
+            .handler(
+                MessageHandler::new(Inspect)
+                    .with_retry(RetryPolicy::new(2).base_delay(Duration::from_millis(1))),
+            )
+            .build();
+        let publisher = broker.get_publisher();
+        tokio::spawn(broker.run());
+
+        let correlation_id = uuid::Uuid::now_v7();
+        let options = SendOptions::new()
+            .correlation_id(correlation_id)
+            .header("slot", "42");
+        publisher.send_with::<Inspect>(1, options).await.unwrap();
+
+        let first = envelopes.recv().await.unwrap();
+        let second = envelopes.recv().await.unwrap();
+        assert_eq!(first.attempt(), 1);
+        assert_eq!(second.attempt(), 2);
+        assert_eq!(first.id(), second.id());
+        assert_eq!(second.correlation_id(), correlation_id);
+        assert_eq!(second.header("slot"), Some("42"));
+        assert_eq!(first.published_at(), second.published_at());
+    }
+
+    #[tokio::test]
+    async fn test_replay_keeps_flow() {
+        let (ctx, mut envelopes) = mpsc::unbounded_channel();
+        let broker = TestBroker::new(
+            MessageBroker::builder(ctx)
+                .handler(MessageHandler::new(Inspect).with_retry(RetryPolicy::none())),
+        );
+        let correlation_id = uuid::Uuid::now_v7();
+        let options = SendOptions::new()
+            .correlation_id(correlation_id)
+            .header("slot", "42")
+            .partition_key("wallet")
+            .priority(Priority::High);
+        broker
+            .publisher()
+            .send_with::<Inspect>(1, options)
+            .await
+            .unwrap();
+        broker.run_until_idle().await;
+
+        let dead_letters = broker.dead_letters();
+        let dead_letter = dead_letters.list().await.unwrap().pop().unwrap();
+        dead_letters.replay(dead_letter.id).await.unwrap();
+        broker.run_until_idle().await;
+
+        let original = envelopes.recv().await.unwrap();
+        let replayed = envelopes.recv().await.unwrap();
+        assert_ne!(replayed.id(), original.id());
+        assert_eq!(replayed.correlation_id(), correlation_id);
+        assert_eq!(replayed.headers(), original.headers());
+        assert_eq!(replayed.partition_key(), Some("wallet"));
+        assert_eq!(replayed.priority(), Priority::High);
+    }
+
+    #[tokio::test]
+    async fn test_middleware_runs_global_then_route() {
+        let (ctx, mut done) = context(1);
+        let broker = MessageBroker::builder(ctx)
+            .handler(MessageHandler::new(Rendezvous))
+            .route_middleware(Rendezvous::ROUTING_KEY, Label(20))
+            .middleware(Label(10))
+            .route_middleware("other", Label(30))
+            .build();
+        let publisher = broker.get_publisher();
+        tokio::spawn(broker.run());
+
+        publisher.send::<Rendezvous>(1).await.unwrap();
+
+        let mut handled = Vec::new();
+        for _ in 0..3 {
+            handled.extend(done.recv().await);
+        }
+        assert_eq!(handled, vec![10, 20, 1]);
+    }
+
+    #[tokio::test]
+    async fn test_timeout_middleware_fails_hung_handlers() {
+        let (ctx, _done) = context(2);
+        let broker = MessageBroker::builder(ctx)
+            .handler(MessageHandler::new(Rendezvous).with_retry(RetryPolicy::none()))
+            .middleware(Timeout::new(Duration::from_millis(10)))
+            .build();
+        let publisher = broker.get_publisher();
+        let dead_letters = broker.dead_letters();
+        tokio::spawn(broker.run());
+
+        publisher.send::<Rendezvous>(1).await.unwrap();
+
+        let dead_letter = wait_for_dead_letter(&dead_letters).await;
+        assert_eq!(dead_letter.error, "handler timed out after 10ms");
+    }
+
+    #[tokio::test]
+    async fn test_batch_handler_receives_batches() {
+        let (ctx, mut batches) = mpsc::unbounded_channel();
+        let broker = MessageBroker::<_, TestError>::builder(ctx)
+            .batch_handler(
+                BatchMessageHandler::new(Batch)
+                    .max_batch_size(2)
+                    .linger(Duration::from_millis(50)),
+            )
+            .build();
+        let publisher = broker.get_publisher();
+        for msg in 1..=3 {
+            publisher.send::<Batched<Batch>>(msg).await.unwrap();
+        }
+        tokio::spawn(broker.run());
+
+        let first = batches.recv().await.unwrap();
+        let second = batches.recv().await.unwrap();
+        assert_eq!(first, vec![1, 2]);
+        assert_eq!(second, vec![3]);
+    }
+
+    #[tokio::test]
+    async fn test_batch_errors_are_per_message() {
+        let (ctx, mut batches) = mpsc::unbounded_channel();
+        let broker = MessageBroker::<_, TestError>::builder(ctx)
+            .batch_handler(BatchMessageHandler::new(Batch).linger(Duration::from_millis(50)))
+            .build();
+        let publisher = broker.get_publisher();
+        let dead_letters = broker.dead_letters();
+        for msg in [1, 0, 2] {
+            publisher.send::<Batched<Batch>>(msg).await.unwrap();
+        }
+        tokio::spawn(broker.run());
+
+        assert_eq!(batches.recv().await.unwrap(), vec![1, 0, 2]);
+        let dead_letter = wait_for_dead_letter(&dead_letters).await;
+        assert_eq!(dead_letter.data, vec![0]);
+        assert_eq!(
+            dead_letter.handler.as_deref(),
+            Some(std::any::type_name::<Batch>())
+        );
+        assert_eq!(dead_letters.list().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_batch_decode_errors_outlive_missing_results() {
+        let broker = MessageBroker::<_, TestError>::builder(())
+            .batch_handler(BatchMessageHandler::new(Forgetful).linger(Duration::from_millis(50)))
+            .build();
+        let publisher = broker.get_publisher();
+        let dead_letters = broker.dead_letters();
+        publisher.send::<Batched<Forgetful>>(1).await.unwrap();
+        publisher.send::<FutureForgetful>(2).await.unwrap();
+        tokio::spawn(broker.run());
+
+        // The first message is retried, the second fails for good right away.
+        let dead_letter = wait_for_dead_letter(&dead_letters).await;
+        assert_eq!(dead_letter.schema_version, 9);
+        assert_eq!(dead_letter.attempts, 1);
+    }
+
+    #[test]
+    fn test_topic_patterns() {
+        assert!(Pattern::new("swap.raydium").matches("swap.raydium"));
+        assert!(!Pattern::new("swap.raydium").matches("swap.orca"));
+        assert!(Pattern::new("swap.*").matches("swap.raydium"));
+        assert!(!Pattern::new("swap.*").matches("swap"));
+        assert!(!Pattern::new("swap.*").matches("swap.raydium.clmm"));
+        assert!(Pattern::new("swap.#").matches("swap"));
+        assert!(Pattern::new("swap.#").matches("swap.raydium.clmm"));
+        assert!(Pattern::new("#.clmm").matches("swap.raydium.clmm"));
+        assert!(!Pattern::new("#.clmm").matches("swap.raydium"));
+    }
+
+    #[tokio::test]
+    async fn test_fan_out_retries_only_failed_handler() {
+        let (ctx, mut done_rx) = flaky_context();
+        let broker = MessageBroker::builder(ctx)
+            .handler(
+                MessageHandler::new(Flaky)
+                    .with_retry(RetryPolicy::new(3).base_delay(Duration::from_millis(1))),
+            )
+            .handler(MessageHandler::new(Echo).subscribe(Flaky::ROUTING_KEY))
+            .build();
+        let publisher = broker.get_publisher();
+        tokio::spawn(broker.run());
+
+        publisher.send::<Flaky>(2).await.unwrap();
+
+        let mut handled = Vec::new();
+        for _ in 0..2 {
+            handled.extend(done_rx.recv().await);
+        }
+        handled.sort();
+        assert_eq!(handled, vec![2, 102]);
+        let res = tokio::time::timeout(Duration::from_millis(100), done_rx.recv()).await;
+        assert!(res.is_err(), "echo should only see the message once");
+    }
+
+    #[tokio::test]
+    async fn test_wildcard_subscription_and_unrouted_dead_letters() {
+        let (ctx, mut done_rx) = flaky_context();
+        let broker = MessageBroker::builder(ctx)
+            .handler(MessageHandler::new(Echo).subscribe("swap.*"))
+            .unrouted(Unrouted::DeadLetter)
+            .build();
+        let publisher = broker.get_publisher();
+        let dead_letters = broker.dead_letters();
+        tokio::spawn(broker.run());
+
+        publisher.send::<Flaky>(1).await.unwrap();
+        publisher.send::<SwapObserved>(2).await.unwrap();
+
+        assert_eq!(done_rx.recv().await, Some(102));
+        let dead_letter = dead_letters.list().await.unwrap().pop().unwrap();
+        assert_eq!(dead_letter.routing_key, Flaky::ROUTING_KEY);
+        assert_eq!(dead_letter.handler, None);
+        assert_eq!(dead_letter.error, "no handler for flaky");
+    }
+
+    #[tokio::test]
+    async fn test_partition_key_keeps_order() {
+        let (ctx, mut done) = mpsc::unbounded_channel();
+        let broker = MessageBroker::builder(ctx)
+            .handler(MessageHandler::new(Sleepy))
+            .workers(4)
+            .build();
+        let publisher = broker.get_publisher();
+        tokio::spawn(broker.run());
+
+        for msg in 1..=5 {
+            let options = SendOptions::new().partition_key("wallet");
+            publisher.send_with::<Sleepy>(msg, options).await.unwrap();
+        }
+
+        let mut handled = Vec::new();
+        for _ in 0..5 {
+            handled.extend(done.recv().await);
+        }
+        assert_eq!(handled, vec![1, 2, 3, 4, 5]);
+    }
+
+    #[tokio::test]
+    async fn test_partitions_run_in_parallel() {
+        let (ctx, mut done) = context(2);
+        let broker = MessageBroker::builder(ctx)
+            .handler(MessageHandler::new(Rendezvous))
+            .partitions(4)
+            .build();
+        let publisher = broker.get_publisher();
+        tokio::spawn(broker.run());
+
+        let other = ["b", "c", "d", "e"]
+            .into_iter()
+            .find(|key| partition(key, 4) != partition("a", 4))
+            .unwrap();
+        for (msg, key) in [(1, "a"), (2, other)] {
+            let options = SendOptions::new().partition_key(key);
+            publisher
+                .send_with::<Rendezvous>(msg, options)
+                .await
+                .unwrap();
+        }
+
+        let mut handled = Vec::new();
+        for _ in 0..2 {
+            let msg = tokio::time::timeout(Duration::from_secs(1), done.recv())
+                .await
+                .expect("partitions did not run in parallel");
+            handled.extend(msg);
+        }
+        handled.sort();
+        assert_eq!(handled, vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_full_lane_does_not_block_receiving() {
+        let (ctx, mut done) = context(2);
+        // The worker that overfills the lane waits for room, the other one
+        // keeps receiving.
+        let broker = MessageBroker::builder(ctx)
+            .handler(MessageHandler::new(Rendezvous))
+            .handler(MessageHandler::new(Meet))
+            .workers(2)
+            .partitions(1)
+            .build();
+        let publisher = broker.get_publisher();
+        tokio::spawn(broker.run());
+
+        for msg in 1..=LANE_CAPACITY as u32 + 1 {
+            let options = SendOptions::new().partition_key("hot");
+            publisher
+                .send_with::<Rendezvous>(msg, options)
+                .await
+                .unwrap();
+        }
+        publisher.send::<Meet>(1).await.unwrap();
+
+        let mut handled = Vec::new();
+        for _ in 0..2 {
+            let msg = tokio::time::timeout(Duration::from_secs(1), done.recv())
+                .await
+                .expect("full lane blocked receiving");
+            handled.extend(msg);
+        }
+        handled.sort();
+        assert_eq!(handled, vec![1, 101]);
+    }
+
+    #[tokio::test]
+    async fn test_full_lane_keeps_order() {
+        let (ctx, mut done) = mpsc::unbounded_channel();
+        let rate_limit = RateLimit::per_second(500).burst(1);
+        let broker = MessageBroker::builder(ctx)
+            .handler(MessageHandler::new(Sleepy).with_rate_limit(rate_limit))
+            .workers(4)
+            .partitions(1)
+            .build();
+        let publisher = broker.get_publisher();
+        tokio::spawn(broker.run());
+
+        let count = 3 * LANE_CAPACITY as u32;
+        for msg in 1..=count {
+            let options = SendOptions::new().partition_key("wallet");
+            publisher.send_with::<Sleepy>(msg, options).await.unwrap();
+        }
+
+        let mut handled = Vec::new();
+        for _ in 1..=count {
+            let msg = tokio::time::timeout(Duration::from_secs(1), done.recv())
+                .await
+                .expect("lane stalled");
+            handled.extend(msg);
+        }
+        assert_eq!(handled, (1..=count).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_old_schema_versions_are_upgraded() {
+        let (ctx, mut done) = mpsc::unbounded_channel();
+        let broker = MessageBroker::builder(ctx)
+            .handler(MessageHandler::new(Swaps))
+            .build();
+        let publisher = broker.get_publisher();
+        tokio::spawn(broker.run());
+
+        let old = SwapV1 {
+            signature: "old".to_string(),
+        };
+        publisher.send::<OldSwaps>(old).await.unwrap();
+        let new = Swap {
+            signature: "new".to_string(),
+            slot: Some(42),
+        };
+        publisher.send::<Swaps>(new).await.unwrap();
+
+        assert_eq!(done.recv().await, Some(("old".to_string(), None)));
+        assert_eq!(done.recv().await, Some(("new".to_string(), Some(42))));
+    }
+
+    #[test]
+    fn test_codec_header_is_checked() {
+        let decoding = crate::codec::Decoding::<Swap>::new(2, Swaps::decoders());
+        let data = serde_json::to_vec(&serde_json::json!({"signature": "sig"})).unwrap();
+        let envelope = |version: &str, codec: &str| {
+            let mut headers = BTreeMap::new();
+            headers.insert(SCHEMA_VERSION_HEADER.to_string(), version.to_string());
+            headers.insert(CODEC_HEADER.to_string(), codec.to_string());
+            Envelope::new(None, headers, None)
+        };
+
+        assert!(decoding.decode(&envelope("2", "json"), &data).is_ok());
+        assert!(decoding.decode(&envelope("1", "json"), &data).is_ok());
+        assert!(matches!(
+            decoding.decode(&envelope("2", "bincode"), &data),
+            Err(CodecError::UnexpectedCodec { .. })
+        ));
+        assert!(matches!(
+            decoding.decode(&envelope("1", "borsh"), &data),
+            Err(CodecError::UnexpectedCodec { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_delayed_messages_are_held_back() {
+        let (ctx, mut done) = context(1);
+        let broker = MessageBroker::builder(ctx)
+            .handler(MessageHandler::new(Rendezvous))
+            .build();
+        let publisher = broker.get_publisher();
+        tokio::spawn(broker.run());
+
+        let start = std::time::Instant::now();
+        let at = std::time::SystemTime::now() + Duration::from_millis(50);
+        publisher.send_at::<Rendezvous>(at, 1).await.unwrap();
+        publisher
+            .send_after::<Rendezvous>(Duration::from_millis(25), 2)
+            .await
+            .unwrap();
+        publisher.send::<Rendezvous>(3).await.unwrap();
+
+        let mut handled = Vec::new();
+        for _ in 0..3 {
+            handled.extend(done.recv().await);
+        }
+        assert_eq!(handled, vec![3, 2, 1]);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn test_requests_get_replies() {
+        let (ctx, _done) = context(1);
+        let broker = MessageBroker::builder(ctx)
+            .handler(MessageHandler::request(Double))
+            .build();
+        let publisher = broker.get_publisher();
+        tokio::spawn(broker.run());
+
+        assert_eq!(publisher.request::<Double>(21).await.unwrap(), 42);
+        let res = publisher.request::<Double>(0).await;
+        assert!(matches!(res, Err(RequestError::Handler(_))));
+    }
+
+    #[tokio::test]
+    async fn test_requests_follow_send_options() {
+        let (ctx, _done) = context(1);
+        let broker = MessageBroker::builder(ctx)
+            .handler(MessageHandler::request(Double))
+            .transport(InMemoryTransport::new(1))
+            .build();
+        let publisher = broker
+            .get_publisher()
+            .with_request_timeout(Duration::from_millis(50));
+
+        // Unanswered once the queue is full, so dropping it must fail.
+        publisher.send::<Rendezvous>(1).await.unwrap();
+        let drop = publisher.clone().with_backpressure(Backpressure::Drop);
+        let res = drop.request::<Double>(21).await;
+        assert!(matches!(
+            res,
+            Err(RequestError::Publish(PublishError::Full))
+        ));
+        assert_eq!(publisher.dropped(), 1);
+
+        tokio::spawn(broker.run());
+        let start = std::time::Instant::now();
+        let delayed = SendOptions::new().delay(Duration::from_millis(100));
+        assert_eq!(
+            publisher.request_with::<Double>(21, delayed).await.unwrap(),
+            42
+        );
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn test_unanswered_requests_time_out() {
+        let (ctx, _done) = context(1);
+        let broker = MessageBroker::<_, TestError>::builder(ctx)
+            .unrouted(Unrouted::Drop)
+            .build();
+        let publisher = broker
+            .get_publisher()
+            .with_request_timeout(Duration::from_millis(50));
+        tokio::spawn(broker.run());
+
+        let res = publisher.request::<Double>(21).await;
+        assert!(matches!(res, Err(RequestError::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn test_open_breaker_waits_for_probe() {
+        let (ctx, mut done_rx) = flaky_context();
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(1))
+            .probe_interval(Duration::from_millis(50));
+        let retry_policy = RetryPolicy::new(5).base_delay(Duration::from_millis(1));
+        let broker = MessageBroker::builder(ctx)
+            .handler(MessageHandler::new(Flaky).with_retry(retry_policy))
+            .circuit_breaker(Flaky::ROUTING_KEY, breaker)
+            .build();
+        let admin = broker.admin();
+        let publisher = broker.get_publisher();
+        tokio::spawn(broker.run());
+
+        let start = std::time::Instant::now();
+        publisher.send::<Flaky>(3).await.unwrap();
+
+        assert_eq!(done_rx.recv().await, Some(3));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(admin.state(Flaky::ROUTING_KEY), BreakerState::Closed);
+    }
+
+    #[tokio::test]
+    async fn test_paused_keys_wait_for_resume() {
+        let (ctx, mut done) = context(1);
+        let broker = MessageBroker::builder(ctx)
+            .handler(MessageHandler::new(Rendezvous))
+            .build();
+        let admin = broker.admin();
+        let publisher = broker.get_publisher();
+        tokio::spawn(broker.run());
+
+        admin.pause(Rendezvous::ROUTING_KEY);
+        assert_eq!(admin.state(Rendezvous::ROUTING_KEY), BreakerState::Paused);
+        publisher.send::<Rendezvous>(1).await.unwrap();
+        let res = tokio::time::timeout(Duration::from_millis(100), done.recv()).await;
+        assert!(res.is_err(), "paused key was dispatched");
+
+        admin.resume(Rendezvous::ROUTING_KEY);
+        assert_eq!(done.recv().await, Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_paces_handler() {
+        let (ctx, mut done) = context(1);
+        let rate_limit = RateLimit::per_second(20).burst(1);
+        let broker = MessageBroker::builder(ctx)
+            .handler(MessageHandler::new(Rendezvous).with_rate_limit(rate_limit))
+            .build();
+        let publisher = broker.get_publisher();
+        tokio::spawn(broker.run());
+
+        let start = std::time::Instant::now();
+        for msg in 1..=3 {
+            publisher.send::<Rendezvous>(msg).await.unwrap();
+        }
+        for msg in 1..=3 {
+            assert_eq!(done.recv().await, Some(msg));
+        }
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_rate_limit_reservations_are_kept() {
+        let bucket = TokenBucket::new(RateLimit::per_second(10).burst(1));
+        let [first, second, third] = [(); 3].map(|_| uuid::Uuid::now_v7());
+
+        assert_eq!(bucket.reserve(first), Duration::ZERO);
+        bucket.settle(first);
+        let wait = bucket.reserve(second);
+        assert!(wait > Duration::from_millis(90), "{:?}", wait);
+        assert!(bucket.reserve(third) > wait);
+        // Coming back early keeps the reservation instead of queueing again.
+        assert!(bucket.reserve(second) <= wait);
+        assert!(bucket.reserve(third) <= Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_key_does_not_block_others() {
+        let (ctx, mut done) = context(1);
+        let rate_limit = RateLimit::per_second(1).burst(1);
+        let broker = MessageBroker::builder(ctx)
+            .handler(MessageHandler::new(Rendezvous).with_rate_limit(rate_limit))
+            .handler(MessageHandler::new(Meet))
+            .workers(1)
+            .build();
+        let publisher = broker.get_publisher();
+        tokio::spawn(broker.run());
+
+        for msg in 1..=2 {
+            publisher.send::<Rendezvous>(msg).await.unwrap();
+        }
+        publisher.send::<Meet>(1).await.unwrap();
+
+        for expected in [1, 101] {
+            let msg = tokio::time::timeout(Duration::from_millis(500), done.recv())
+                .await
+                .expect("rate limited key blocked the other");
+            assert_eq!(msg, Some(expected));
+        }
+        assert_eq!(done.recv().await, Some(2));
+    }
+
+    #[tokio::test]
+    async fn test_panics_are_fatal_and_counted() {
+        let (done, mut done_rx) = mpsc::unbounded_channel();
+        let broker = MessageBroker::builder(done)
+            .handler(MessageHandler::new(Panicky))
+            .build();
+        let publisher = broker.get_publisher();
+        let dead_letters = broker.dead_letters();
+        let stats = broker.stats();
+        tokio::spawn(broker.run());
+
+        publisher.send::<Panicky>(0).await.unwrap();
+        publisher.send::<Panicky>(1).await.unwrap();
+
+        assert_eq!(done_rx.recv().await, Some(1));
+        assert_eq!(stats.panics(), 1);
+        let dead_letter = dead_letters.list().await.unwrap().pop().unwrap();
+        assert_eq!(dead_letter.routing_key, Panicky::ROUTING_KEY);
+    }
+
+    #[tokio::test]
+    async fn test_test_broker_steps_through_retries() {
+        let (ctx, _done) = flaky_context();
+        let retry_policy = RetryPolicy::new(2).base_delay(Duration::from_secs(60));
+        let broker = TestBroker::new(
+            MessageBroker::builder(ctx)
+                .handler(MessageHandler::new(Flaky).with_retry(retry_policy)),
+        );
+        broker.publisher().send::<Flaky>(3).await.unwrap();
+        assert_eq!(broker.published().len(), 1);
+
+        let step = broker.step().await.unwrap();
+        assert_eq!(step.attempt(), 1);
+        assert!(step.retried && !step.parked);
+        let (_, res) = &step.results[0];
+        assert!(matches!(
+            res,
+            Err(HandlerError::Transient(TestError::Transient))
+        ));
+
+        let step = broker.step().await.unwrap();
+        assert_eq!(step.attempt(), 2);
+        assert!(!step.retried);
+        assert!(!step.is_ok());
+        assert!(broker.step().await.is_none());
+        assert_eq!(broker.dead_letters().list().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_test_broker_runs_batches() {
+        let (ctx, mut batches) = mpsc::unbounded_channel();
+        let broker = TestBroker::new(
+            MessageBroker::<_, TestError>::builder(ctx)
+                .batch_handler(BatchMessageHandler::new(Batch).max_batch_size(2)),
+        );
+        for msg in [1, 0, 2] {
+            broker
+                .publisher()
+                .send::<Batched<Batch>>(msg)
+                .await
+                .unwrap();
+        }
+
+        let steps = broker.run_until_idle().await;
+        assert_eq!(batches.try_recv().ok(), Some(vec![1, 0]));
+        assert_eq!(batches.try_recv().ok(), Some(vec![2]));
+        let handled = steps.iter().map(Step::is_ok).collect::<Vec<_>>();
+        assert_eq!(handled, vec![true, false, true]);
+        assert!(steps.iter().all(|step| !step.retried && !step.parked));
+        assert_eq!(broker.dead_letters().list().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_run_until_idle_returns_while_paused() {
+        let (ctx, mut done) = flaky_context();
+        let broker =
+            TestBroker::new(MessageBroker::builder(ctx).handler(MessageHandler::new(Echo)));
+        broker.admin().pause(Echo::ROUTING_KEY);
+        for msg in 1..=2 {
+            broker.publisher().send::<Echo>(msg).await.unwrap();
+        }
+
+        let steps = broker.run_until_idle().await;
+        assert_eq!(steps.len(), 2);
+        assert!(steps.iter().all(|step| step.parked && !step.retried));
+        assert_eq!(broker.pending(), 2);
+
+        broker.admin().resume(Echo::ROUTING_KEY);
+        assert_eq!(broker.run_until_idle().await.len(), 2);
+        assert_eq!(done.try_recv().ok(), Some(101));
+        assert_eq!(done.try_recv().ok(), Some(102));
+    }
+
+    #[tokio::test]
+    async fn test_context_publishes_through_broker() {
+        let publisher = Publisher::new(Arc::new(InMemoryTransport::default()));
+        let broker = MessageBroker::<_, TestError>::builder(publisher.clone())
+            .publisher(&publisher)
+            .build();
+        let dead_letters = broker.dead_letters();
+        let stats = broker.stats();
+        tokio::spawn(broker.run());
+
+        publisher.send::<Echo>(1).await.unwrap();
+        let dead_letter = wait_for_dead_letter(&dead_letters).await;
+        assert_eq!(dead_letter.routing_key, Echo::ROUTING_KEY);
+        assert_eq!(stats.route(Echo::ROUTING_KEY).published, 1);
+    }
+
+    #[tokio::test]
+    async fn test_fake_publisher_captures_messages() {
+        let fake = FakePublisher::new();
+        fake.publisher().send::<Rendezvous>(7).await.unwrap();
+        fake.publisher().send::<Flaky>(8).await.unwrap();
+
+        assert_eq!(fake.published().len(), 2);
+        assert_eq!(fake.decode::<Rendezvous>().unwrap(), vec![7]);
+    }
+
+    #[tokio::test]
+    async fn test_stats_per_routing_key() {
+        let (ctx, _done) = flaky_context();
+        let retry_policy = RetryPolicy::new(3).base_delay(Duration::from_secs(60));
+        let broker = TestBroker::new(
+            MessageBroker::builder(ctx)
+                .handler(MessageHandler::new(Flaky).with_retry(retry_policy)),
+        );
+        let stats = broker.stats();
+        broker.publisher().send::<Flaky>(2).await.unwrap();
+        assert_eq!(stats.route(Flaky::ROUTING_KEY).queue_depth, 1);
+
+        broker.run_until_idle().await;
+        let flaky = stats.route(Flaky::ROUTING_KEY);
+        assert_eq!(flaky.published, 1);
+        assert_eq!(flaky.handled, 1);
+        assert_eq!(flaky.failed_transient, 1);
+        assert_eq!(flaky.failed_fatal, 0);
+        assert_eq!(flaky.retried, 1);
+        assert_eq!(flaky.queue_depth, 0);
+        assert_eq!(flaky.latency.count(), 2);
+
+        let prometheus = stats.prometheus();
+        assert!(prometheus.contains("msg_broker_handled_total{routing_key=\"flaky\"} 1\n"));
+        assert!(
+            prometheus
+                .contains("msg_broker_handler_latency_seconds_count{routing_key=\"flaky\"} 2\n")
+        );
+    }
+
+    #[test]
+    fn test_prometheus_escapes_label_values() {
+        let counters = Arc::new(Counters::default());
+        counters.published("a\"b\\c\nd");
+        let prometheus = BrokerStats::new(counters).prometheus();
+        assert!(
+            prometheus.contains("msg_broker_published_total{routing_key=\"a\\\"b\\\\c\\nd\"} 1\n")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dedup_drops_duplicates() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let window = DedupWindow::new(Duration::from_secs(60), 2);
+        let broker = TestBroker::new(
+            MessageBroker::builder(tx).handler(MessageHandler::new(Idempotent).with_dedup(window)),
+        );
+        let publisher = broker.publisher();
+        for msg in [1, 1, 0, 0, 2, 3, 1] {
+            publisher.send::<Idempotent>(msg).await.unwrap();
+        }
+        broker.run_until_idle().await;
+
+        // Failed keys are forgotten, and 1 fell out once 2 and 3 were seen.
+        let mut handled = Vec::new();
+        while let Ok(msg) = rx.try_recv() {
+            handled.push(msg);
+        }
+        assert_eq!(handled, vec![1, 2, 3, 1]);
+        let stats = broker.stats().route(Idempotent::ROUTING_KEY);
+        assert_eq!(stats.deduplicated, 1);
+        assert_eq!(stats.failed_fatal, 2);
+        assert_eq!(broker.dead_letters().list().await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_idempotency_key_panics_are_fatal() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let window = DedupWindow::new(Duration::from_secs(60), 2);
+        let broker = TestBroker::new(
+            MessageBroker::builder(tx).handler(MessageHandler::new(Idempotent).with_dedup(window)),
+        );
+        let publisher = broker.publisher();
+        for msg in [99, 1] {
+            publisher.send::<Idempotent>(msg).await.unwrap();
+        }
+        broker.run_until_idle().await;
+
+        assert_eq!(rx.try_recv().ok(), Some(1));
+        assert!(rx.try_recv().is_err());
+        assert_eq!(broker.stats().panics(), 1);
+        let dead_letter = broker.dead_letters().list().await.unwrap().pop().unwrap();
+        assert_eq!(dead_letter.routing_key, Idempotent::ROUTING_KEY);
+    }
+
+    #[tokio::test]
+    async fn test_duplicates_do_not_close_breaker() {
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let window = DedupWindow::new(Duration::from_secs(60), 2);
+        let breaker =
+            CircuitBreaker::new(1, Duration::from_secs(60)).probe_interval(Duration::ZERO);
+        let broker = TestBroker::new(
+            MessageBroker::builder(tx)
+                .handler(
+                    MessageHandler::new(Idempotent)
+                        .with_dedup(window)
+                        .with_retry(RetryPolicy::none()),
+                )
+                .circuit_breaker(Idempotent::ROUTING_KEY, breaker),
+        );
+        let admin = broker.admin();
+        let publisher = broker.publisher();
+        for msg in [1, 100, 1] {
+            publisher.send::<Idempotent>(msg).await.unwrap();
+        }
+
+        broker.step().await.unwrap();
+        broker.step().await.unwrap();
+        assert_eq!(admin.state(Idempotent::ROUTING_KEY), BreakerState::Open);
+        // The duplicate is let through as the probe but never handled.
+        broker.step().await.unwrap();
+        assert_eq!(admin.state(Idempotent::ROUTING_KEY), BreakerState::HalfOpen);
+        assert_eq!(
+            broker.stats().route(Idempotent::ROUTING_KEY).deduplicated,
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_higher_priority_lanes_first() {
+        let transport = Arc::new(InMemoryTransport::new(4));
+        let publisher = Publisher::new(transport.clone());
+        for priority in [Priority::Low, Priority::Normal, Priority::High] {
+            let options = SendOptions::new().priority(priority);
+            publisher.send_with::<Echo>(1, options).await.unwrap();
+        }
+        transport.close();
+
+        let mut served = Vec::new();
+        while let Some(delivery) = transport.recv().await.unwrap() {
+            served.push(delivery.message.envelope().priority());
+        }
+        assert_eq!(served, Priority::ALL);
+    }
+
+    #[tokio::test]
+    async fn test_expired_messages_are_discarded() {
+        let (ctx, mut done_rx) = flaky_context();
+        let broker =
+            TestBroker::new(MessageBroker::builder(ctx).handler(MessageHandler::new(Echo)));
+        let publisher = broker.publisher();
+        let ttl = SendOptions::new().ttl(Duration::from_millis(1));
+        publisher.send_with::<Echo>(1, ttl).await.unwrap();
+        let ttl = SendOptions::new().ttl(Duration::from_secs(60));
+        publisher.send_with::<Echo>(2, ttl).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let steps = broker.run_until_idle().await;
+        assert_eq!(steps.len(), 2);
+        assert!(steps[0].results.is_empty());
+        assert_eq!(done_rx.try_recv(), Ok(102));
+        assert!(done_rx.try_recv().is_err());
+        let stats = broker.stats().route(Echo::ROUTING_KEY);
+        assert_eq!(stats.expired, 1);
+        assert_eq!(stats.handled, 1);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_queue() {
+        let (ctx, mut done) = context(1);
+        let shutdown = ShutdownToken::new();
+        let broker = MessageBroker::builder(ctx)
+            .handler(MessageHandler::new(Rendezvous))
+            .shutdown(shutdown.clone())
+            .build();
+        let publisher = broker.get_publisher();
+        for msg in 1..=3 {
+            publisher.send::<Rendezvous>(msg).await.unwrap();
+        }
+
+        shutdown.shutdown();
+        tokio::time::timeout(Duration::from_secs(1), broker.run())
+            .await
+            .expect("broker did not stop");
+        let res = publisher.send::<Rendezvous>(4).await;
+        assert!(matches!(res, Err(PublishError::Closed)));
+        for msg in 1..=3 {
+            assert_eq!(done.try_recv().ok(), Some(msg));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_retries() {
+        let (ctx, mut done) = flaky_context();
+        let shutdown = ShutdownToken::new();
+        let retry_policy = RetryPolicy::new(3).base_delay(Duration::from_millis(20));
+        let broker = MessageBroker::builder(ctx)
+            .handler(MessageHandler::new(Flaky).with_retry(retry_policy))
+            .shutdown(shutdown.clone())
+            .build();
+        let publisher = broker.get_publisher();
+        publisher.send::<Flaky>(2).await.unwrap();
+
+        shutdown.shutdown();
+        tokio::time::timeout(Duration::from_secs(1), broker.run())
+            .await
+            .expect("broker did not stop");
+        assert_eq!(done.try_recv().ok(), Some(2));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_lane_retries() {
+        let (ctx, mut done) = flaky_context();
+        let shutdown = ShutdownToken::new();
+        let retry_policy = RetryPolicy::new(3).base_delay(Duration::from_millis(20));
+        let broker = MessageBroker::builder(ctx)
+            .handler(MessageHandler::new(Flaky).with_retry(retry_policy))
+            .shutdown(shutdown.clone())
+            .build();
+        let publisher = broker.get_publisher();
+        let options = SendOptions::new().partition_key("wallet");
+        publisher.send_with::<Flaky>(2, options).await.unwrap();
+
+        shutdown.shutdown();
+        tokio::time::timeout(Duration::from_secs(1), broker.run())
+            .await
+            .expect("broker did not stop");
+        assert_eq!(done.try_recv().ok(), Some(2));
+    }
+
+    #[tokio::test]
+    async fn test_drain_timeout_dead_letters_leftovers() {
+        let (ctx, mut done) = flaky_context();
+        let shutdown = ShutdownToken::new();
+        let retry_policy = RetryPolicy::new(3).base_delay(Duration::from_secs(10));
+        let broker = MessageBroker::builder(ctx)
+            .handler(MessageHandler::new(Flaky).with_retry(retry_policy))
+            .shutdown(shutdown.clone())
+            .drain_timeout(Duration::from_millis(50))
+            .build();
+        let publisher = broker.get_publisher();
+        let dead_letters = broker.dead_letters();
+        publisher.send::<Flaky>(5).await.unwrap();
+        let options = SendOptions::new().partition_key("wallet");
+        publisher.send_with::<Flaky>(6, options).await.unwrap();
+
+        shutdown.shutdown();
+        tokio::time::timeout(Duration::from_secs(1), broker.run())
+            .await
+            .expect("broker did not stop");
+        assert!(done.try_recv().is_err());
+        let mut left = dead_letters.list().await.unwrap();
+        left.sort_by_key(|dead_letter| dead_letter.decode::<Flaky>().unwrap());
+        assert_eq!(left.len(), 2);
+        for (dead_letter, msg) in left.iter().zip([5, 6]) {
+            assert_eq!(dead_letter.decode::<Flaky>().unwrap(), msg);
+            assert_eq!(dead_letter.error, "broker shut down before it was handled");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_closed_transport_refuses_messages_right_away() {
+        let transport = InMemoryTransport::default();
+        transport.close();
+        let res = transport.send(wire_message()).await;
+        assert!(matches!(res, Err(TransportError::Closed)));
+        let res = transport.try_send(wire_message()).await;
+        assert!(matches!(res, Err(TransportError::Closed)));
+        let res = transport.send_after(wire_message(), Duration::ZERO).await;
+        assert!(matches!(res, Err(TransportError::Closed)));
+        assert!(transport.recv().await.unwrap().is_none());
+    }
+
+    fn wire_message() -> Message {
+        let mut headers = BTreeMap::new();
+        headers.insert("source".to_string(), "test".to_string());
+        let mut envelope = Envelope::new(None, headers, Some("wallet".to_string()));
+        envelope.handlers.push("Echo".to_string());
+        Message {
+            routing_key: "echo".to_string(),
+            data: vec![1, 2, 3],
+            envelope,
+        }
+    }
+
+    #[test]
+    fn test_wire_round_trip() {
+        let msg = wire_message();
+        let data = wire::encode(&msg).unwrap();
+        let decoded = wire::decode(&data).unwrap();
+        assert_eq!(decoded.routing_key, msg.routing_key);
+        assert_eq!(decoded.data, msg.data);
+        assert_eq!(decoded.envelope, msg.envelope);
+
+        let mut data = data;
+        data[1] += 1;
+        assert!(wire::decode(&data).is_err());
+
+        let config = bincode::config::standard();
+        let unversioned = (&msg.routing_key, &msg.data, &msg.envelope);
+        let data = bincode::encode_to_vec(unversioned, config).unwrap();
+        assert!(wire::decode(&data).is_err());
     }
 }
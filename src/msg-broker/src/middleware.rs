@@ -0,0 +1,153 @@
+use std::{
+    fmt,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    Envelope, HandlerError,
+    handler_trait::{BoxFuture, InnerHandler},
+    request::Replies,
+};
+
+/// The message a middleware is asked to handle.
+pub struct Request<'a, Ctx> {
+    pub routing_key: &'a str,
+    pub context: Arc<Ctx>,
+    pub data: &'a [u8],
+    pub envelope: &'a Envelope,
+}
+
+/// The rest of the chain, ending with the handler itself.
+pub struct Next<'a, Ctx, Err> {
+    middleware: &'a [Arc<dyn Middleware<Ctx, Err>>],
+    handler: &'a dyn InnerHandler<Context = Ctx, Error = Err>,
+    replies: &'a Replies,
+}
+
+impl<'a, Ctx, Err> Next<'a, Ctx, Err>
+where
+    Ctx: 'static,
+    Err: 'static,
+{
+    pub(crate) fn new(
+        middleware: &'a [Arc<dyn Middleware<Ctx, Err>>],
+        handler: &'a dyn InnerHandler<Context = Ctx, Error = Err>,
+        replies: &'a Replies,
+    ) -> Self {
+        Next {
+            middleware,
+            handler,
+            replies,
+        }
+    }
+
+    pub fn run(self, req: Request<'a, Ctx>) -> BoxFuture<'a, Result<(), HandlerError<Err>>> {
+        match self.middleware.split_first() {
+            Some((middleware, rest)) => {
+                middleware.handle(req, Next::new(rest, self.handler, self.replies))
+            }
+            None => self
+                .handler
+                .handle(req.context, req.data, req.envelope.clone(), self.replies),
+        }
+    }
+}
+
+/// Wraps every call to a handler, see [`crate::MessageBrokerBuilder::middleware`].
+pub trait Middleware<Ctx, Err>: Send + Sync + 'static {
+    fn handle<'a>(
+        &'a self,
+        req: Request<'a, Ctx>,
+        next: Next<'a, Ctx, Err>,
+    ) -> BoxFuture<'a, Result<(), HandlerError<Err>>>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandlerTimeout {
+    pub timeout: Duration,
+}
+
+impl fmt::Display for HandlerTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "handler timed out after {:?}", self.timeout)
+    }
+}
+
+impl std::error::Error for HandlerTimeout {}
+
+/// Fails handlers running longer than the timeout with a transient
+/// [`HandlerError::Timeout`].
+#[derive(Debug, Clone, Copy)]
+pub struct Timeout {
+    timeout: Duration,
+}
+
+impl Timeout {
+    pub fn new(timeout: Duration) -> Self {
+        Timeout { timeout }
+    }
+}
+
+impl<Ctx, Err> Middleware<Ctx, Err> for Timeout
+where
+    Ctx: Send + Sync + 'static,
+    Err: Send + 'static,
+{
+    fn handle<'a>(
+        &'a self,
+        req: Request<'a, Ctx>,
+        next: Next<'a, Ctx, Err>,
+    ) -> BoxFuture<'a, Result<(), HandlerError<Err>>> {
+        Box::pin(async move {
+            match tokio::time::timeout(self.timeout, next.run(req)).await {
+                Ok(res) => res,
+                Err(_) => Err(HandlerError::Timeout(HandlerTimeout {
+                    timeout: self.timeout,
+                })),
+            }
+        })
+    }
+}
+
+/// Logs how long each message took and why it failed.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Trace;
+
+impl<Ctx, Err> Middleware<Ctx, Err> for Trace
+where
+    Ctx: Send + Sync + 'static,
+    Err: fmt::Debug + Send + 'static,
+{
+    fn handle<'a>(
+        &'a self,
+        req: Request<'a, Ctx>,
+        next: Next<'a, Ctx, Err>,
+    ) -> BoxFuture<'a, Result<(), HandlerError<Err>>> {
+        Box::pin(async move {
+            let routing_key = req.routing_key;
+            let id = req.envelope.id();
+            let attempt = req.envelope.attempt();
+            let start = Instant::now();
+            let res = next.run(req).await;
+            match &res {
+                Ok(()) => println!(
+                    "INFO: handled {} ({}) attempt {} in {:?}",
+                    routing_key,
+                    id,
+                    attempt,
+                    start.elapsed()
+                ),
+                Err(err) => println!(
+                    "WARN: failed {} ({}) attempt {} in {:?}: {:?}",
+                    routing_key,
+                    id,
+                    attempt,
+                    start.elapsed(),
+                    err
+                ),
+            }
+            res
+        })
+    }
+}
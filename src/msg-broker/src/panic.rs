@@ -0,0 +1,54 @@
+use std::{
+    any::Any,
+    fmt,
+    panic::{self, AssertUnwindSafe},
+    pin::Pin,
+    task::Poll,
+};
+
+/// A handler panicked, always fatal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HandlerPanic {
+    pub message: String,
+}
+
+impl HandlerPanic {
+    fn new(payload: Box<dyn Any + Send>) -> Self {
+        let message = match payload.downcast::<String>() {
+            Ok(message) => *message,
+            Err(payload) => match payload.downcast_ref::<&str>() {
+                Some(message) => message.to_string(),
+                None => "unknown panic".to_string(),
+            },
+        };
+        HandlerPanic { message }
+    }
+}
+
+impl fmt::Display for HandlerPanic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "handler panicked: {}", self.message)
+    }
+}
+
+impl std::error::Error for HandlerPanic {}
+
+/// Runs `fut`, turning a panic while polling it into a [`HandlerPanic`].
+pub(crate) async fn catch_unwind<F>(mut fut: F) -> Result<F::Output, HandlerPanic>
+where
+    F: Future + Unpin,
+{
+    std::future::poll_fn(|cx| {
+        match panic::catch_unwind(AssertUnwindSafe(|| Pin::new(&mut fut).poll(cx))) {
+            Ok(Poll::Ready(output)) => Poll::Ready(Ok(output)),
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(payload) => Poll::Ready(Err(HandlerPanic::new(payload))),
+        }
+    })
+    .await
+}
+
+/// Runs `f`, turning a panic into a [`HandlerPanic`].
+pub(crate) fn catch_unwind_sync<R>(f: impl FnOnce() -> R) -> Result<R, HandlerPanic> {
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(HandlerPanic::new)
+}
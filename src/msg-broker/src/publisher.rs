@@ -0,0 +1,369 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, SystemTime},
+};
+
+use uuid::Uuid;
+
+use crate::{
+    CODEC_HEADER, Codec, CodecError, DeadLetter, Envelope, Message, Payload, Priority,
+    RequestError, RequestHandler, Route, SCHEMA_VERSION_HEADER, Transport, TransportError,
+    request::Replies, stats::Counters,
+};
+
+#[derive(thiserror::Error, Debug)]
+pub enum PublishError {
+    #[error(transparent)]
+    Codec(#[from] CodecError),
+    #[error("broker queue is full")]
+    Full,
+    #[error("broker is closed")]
+    Closed,
+    #[error(transparent)]
+    Transport(TransportError),
+}
+
+impl From<TransportError> for PublishError {
+    fn from(value: TransportError) -> Self {
+        match value {
+            TransportError::Full => PublishError::Full,
+            TransportError::Closed => PublishError::Closed,
+            err => PublishError::Transport(err),
+        }
+    }
+}
+
+/// What [`Publisher::send`] does when the broker queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backpressure {
+    /// Wait until there is room.
+    #[default]
+    Block,
+    /// Drop the message and count it, see [`Publisher::dropped`].
+    Drop,
+    /// Return [`PublishError::Full`].
+    FailFast,
+}
+
+/// Envelope fields set by the publisher.
+#[derive(Debug, Clone, Default)]
+pub struct SendOptions {
+    correlation_id: Option<Uuid>,
+    headers: BTreeMap<String, String>,
+    partition_key: Option<String>,
+    delay: Option<Duration>,
+    priority: Priority,
+    ttl: Option<Duration>,
+}
+
+impl SendOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn correlation_id(mut self, correlation_id: Uuid) -> Self {
+        self.correlation_id = Some(correlation_id);
+        self
+    }
+
+    pub fn header(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.insert(key.into(), value.into());
+        self
+    }
+
+    /// Keeps the message in order with every other message sharing `key`,
+    /// like all swaps of one wallet, see [`crate::MessageBrokerBuilder::partitions`].
+    /// Only a retry after a transient error goes back through the queue, and
+    /// can be overtaken by later messages with the same key.
+    pub fn partition_key(mut self, key: impl Into<String>) -> Self {
+        self.partition_key = Some(key.into());
+        self
+    }
+
+    /// Holds the message back until `delay` has passed. Delayed messages
+    /// never count against the capacity of the queue.
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = Some(delay);
+        self
+    }
+
+    /// Holds the message back until `at`, delivering it right away if `at`
+    /// has already passed.
+    pub fn deliver_at(self, at: SystemTime) -> Self {
+        let delay = at.duration_since(SystemTime::now()).unwrap_or_default();
+        self.delay(delay)
+    }
+
+    /// Higher priority messages are dispatched first, [`Priority::Normal`]
+    /// by default.
+    pub fn priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Discards the message if it is still queued `ttl` after it was
+    /// published, retries included. Discarded messages are counted in
+    /// [`crate::RouteStats::expired`].
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+}
+
+#[derive(Clone)]
+pub struct Publisher {
+    pub(crate) transport: Arc<dyn Transport>,
+    backpressure: Backpressure,
+    dropped: Arc<AtomicU64>,
+    pub(crate) replies: Arc<Replies>,
+    request_timeout: Duration,
+    pub(crate) counters: Arc<Counters>,
+}
+
+impl Publisher {
+    /// Publishes straight to `transport`, for processes that publish without
+    /// running a broker. Only publishers from
+    /// [`crate::MessageBroker::get_publisher`] get replies to
+    /// [`Publisher::request`].
+    pub fn new(transport: Arc<dyn Transport>) -> Self {
+        Publisher {
+            transport,
+            backpressure: Backpressure::default(),
+            dropped: Arc::new(AtomicU64::new(0)),
+            replies: Arc::new(Replies::default()),
+            request_timeout: Duration::from_secs(30),
+            counters: Arc::new(Counters::default()),
+        }
+    }
+
+    pub(crate) fn with_replies(mut self, replies: Arc<Replies>) -> Self {
+        self.replies = replies;
+        self
+    }
+
+    pub(crate) fn with_counters(mut self, counters: Arc<Counters>) -> Self {
+        self.counters = counters;
+        self
+    }
+
+    /// How long [`Publisher::request`] waits for a reply, 30 seconds by
+    /// default.
+    pub fn with_request_timeout(mut self, request_timeout: Duration) -> Self {
+        self.request_timeout = request_timeout;
+        self
+    }
+
+    pub fn with_backpressure(mut self, backpressure: Backpressure) -> Self {
+        self.backpressure = backpressure;
+        self
+    }
+
+    /// Messages dropped by this publisher and its clones under
+    /// [`Backpressure::Drop`].
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub async fn send<T>(&self, msg: T::Msg) -> Result<(), PublishError>
+    where
+        T: Route,
+    {
+        self.send_with::<T>(msg, SendOptions::default()).await
+    }
+
+    /// Delivers `msg` once `delay` has passed, see [`SendOptions::delay`].
+    pub async fn send_after<T>(&self, delay: Duration, msg: T::Msg) -> Result<(), PublishError>
+    where
+        T: Route,
+    {
+        self.send_with::<T>(msg, SendOptions::new().delay(delay))
+            .await
+    }
+
+    /// Delivers `msg` at `at`, see [`SendOptions::deliver_at`].
+    pub async fn send_at<T>(&self, at: SystemTime, msg: T::Msg) -> Result<(), PublishError>
+    where
+        T: Route,
+    {
+        self.send_with::<T>(msg, SendOptions::new().deliver_at(at))
+            .await
+    }
+
+    pub async fn send_with<T>(&self, msg: T::Msg, options: SendOptions) -> Result<(), PublishError>
+    where
+        T: Route,
+    {
+        let delay = options.delay;
+        let msg = Self::encode::<T>(msg, options)?;
+        self.publish(msg, delay).await?;
+        Ok(())
+    }
+
+    /// Never waits for room in the queue, fails with [`PublishError::Full`]
+    /// instead.
+    pub async fn try_send<T>(&self, msg: T::Msg) -> Result<(), PublishError>
+    where
+        T: Route,
+    {
+        self.try_send_with::<T>(msg, SendOptions::default()).await
+    }
+
+    pub async fn try_send_with<T>(
+        &self,
+        msg: T::Msg,
+        options: SendOptions,
+    ) -> Result<(), PublishError>
+    where
+        T: Route,
+    {
+        let delay = options.delay;
+        let msg = Self::encode::<T>(msg, options)?;
+        let routing_key = msg.routing_key.clone();
+        match delay {
+            Some(delay) => self.transport.send_after(msg, delay).await?,
+            None => self.transport.try_send(msg).await?,
+        }
+        self.counters.published(&routing_key);
+        Ok(())
+    }
+
+    /// Sends `msg` to request handler `H` and waits for its reply. A request
+    /// that ends up dead lettered fails with [`RequestError::Handler`].
+    pub async fn request<H>(&self, msg: H::Msg) -> Result<H::Reply, RequestError>
+    where
+        H: RequestHandler,
+    {
+        self.request_with::<H>(msg, SendOptions::default()).await
+    }
+
+    /// Sends like [`Publisher::send_with`], backpressure included. A request
+    /// dropped under [`Backpressure::Drop`] fails with [`PublishError::Full`]
+    /// instead, as its reply would never come. The request timeout starts
+    /// once a delayed request is due.
+    pub async fn request_with<H>(
+        &self,
+        msg: H::Msg,
+        options: SendOptions,
+    ) -> Result<H::Reply, RequestError>
+    where
+        H: RequestHandler,
+    {
+        let delay = options.delay;
+        let data = <H::Msg as Payload>::Codec::encode(&msg)?;
+        let codec = <H::Msg as Payload>::Codec::NAME;
+        let mut msg = Self::message(H::ROUTING_KEY, H::SCHEMA_VERSION, codec, data, options);
+        msg.envelope.reply = true;
+        let id = msg.envelope.id();
+
+        let reply = self.replies.register(id);
+        let sent = match self.publish(msg, delay).await {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(PublishError::Full),
+            Err(err) => Err(err),
+        };
+        if let Err(err) = sent {
+            self.replies.cancel(id);
+            return Err(err.into());
+        }
+        let timeout = self.request_timeout + delay.unwrap_or_default();
+        match tokio::time::timeout(timeout, reply).await {
+            Ok(Ok(Ok(data))) => Ok(<H::Reply as Payload>::Codec::decode(&data)?),
+            Ok(Ok(Err(error))) => Err(RequestError::Handler(error)),
+            Ok(Err(_)) => Err(RequestError::Closed),
+            Err(_) => {
+                self.replies.cancel(id);
+                Err(RequestError::Timeout)
+            }
+        }
+    }
+
+    /// Publishes a dead letter again as a new message in the same flow, with
+    /// its correlation id, headers, partition key and priority.
+    pub async fn replay(&self, dead_letter: DeadLetter) -> Result<(), PublishError> {
+        let mut headers = dead_letter.headers;
+        headers.insert(
+            SCHEMA_VERSION_HEADER.to_string(),
+            dead_letter.schema_version.to_string(),
+        );
+        let mut envelope = Envelope::new(
+            dead_letter.correlation_id,
+            headers,
+            dead_letter.partition_key,
+        );
+        envelope.priority = dead_letter.priority;
+        envelope.handlers.extend(dead_letter.handler);
+        let msg = Message {
+            routing_key: dead_letter.routing_key,
+            data: dead_letter.data,
+            envelope,
+        };
+        let routing_key = msg.routing_key.clone();
+        self.transport.send(msg).await?;
+        self.counters.published(&routing_key);
+        Ok(())
+    }
+
+    /// Hands `msg` to the transport under this publisher's backpressure,
+    /// `false` if it was dropped.
+    async fn publish(&self, msg: Message, delay: Option<Duration>) -> Result<bool, PublishError> {
+        let routing_key = msg.routing_key.clone();
+        match (delay, self.backpressure) {
+            (Some(delay), _) => self.transport.send_after(msg, delay).await?,
+            (None, Backpressure::Block) => self.transport.send(msg).await?,
+            (None, Backpressure::Drop) => match self.transport.try_send(msg).await {
+                Err(TransportError::Full) => {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    return Ok(false);
+                }
+                res => res?,
+            },
+            (None, Backpressure::FailFast) => self.transport.try_send(msg).await?,
+        }
+        self.counters.published(&routing_key);
+        Ok(true)
+    }
+
+    fn encode<T>(msg: T::Msg, options: SendOptions) -> Result<Message, PublishError>
+    where
+        T: Route,
+    {
+        let data = <T::Msg as Payload>::Codec::encode(&msg)?;
+        Ok(Self::message(
+            T::ROUTING_KEY,
+            T::SCHEMA_VERSION,
+            <T::Msg as Payload>::Codec::NAME,
+            data,
+            options,
+        ))
+    }
+
+    fn message(
+        routing_key: &str,
+        schema_version: u32,
+        codec: &str,
+        data: Vec<u8>,
+        options: SendOptions,
+    ) -> Message {
+        let options = options
+            .header(SCHEMA_VERSION_HEADER, schema_version.to_string())
+            .header(CODEC_HEADER, codec);
+        let mut envelope = Envelope::new(
+            options.correlation_id,
+            options.headers,
+            options.partition_key,
+        );
+        envelope.priority = options.priority;
+        if let Some(ttl) = options.ttl {
+            envelope.expire_after(ttl);
+        }
+        Message {
+            routing_key: routing_key.to_string(),
+            data,
+            envelope,
+        }
+    }
+}
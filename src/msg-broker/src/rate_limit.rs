@@ -0,0 +1,108 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, MutexGuard},
+    time::Duration,
+};
+
+use tokio::time::Instant;
+use uuid::Uuid;
+
+/// A token bucket for [`crate::MessageHandler::with_rate_limit`]. Every
+/// message takes a token, tokens refill at a steady rate and up to `burst` of
+/// them can be saved up.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    per_second: f64,
+    burst: u32,
+}
+
+impl RateLimit {
+    /// `per_second` messages a second, with a burst of one second's worth.
+    pub fn per_second(per_second: u32) -> Self {
+        let per_second = per_second.max(1);
+        RateLimit {
+            per_second: per_second.into(),
+            burst: per_second,
+        }
+    }
+
+    /// How many messages can be handled back to back after a quiet period.
+    pub fn burst(mut self, burst: u32) -> Self {
+        self.burst = burst.max(1);
+        self
+    }
+}
+
+/// Reservations are forgotten this long after they came due, in case their
+/// message never came back for them.
+const RESERVATION_TTL: Duration = Duration::from_secs(60);
+
+/// The bucket of one handler, starts full. A message takes its token when it
+/// first asks, even if the token is yet to refill, and keeps that reservation
+/// until it is dispatched, so parked messages come back in turn.
+pub(crate) struct TokenBucket {
+    limit: RateLimit,
+    state: Mutex<State>,
+}
+
+struct State {
+    /// Below zero while tokens are reserved ahead of the refill.
+    tokens: f64,
+    refilled_at: Instant,
+    /// When each message waiting for a token may have it.
+    reservations: HashMap<Uuid, Instant>,
+    pruned_at: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(limit: RateLimit) -> Self {
+        let now = Instant::now();
+        TokenBucket {
+            limit,
+            state: Mutex::new(State {
+                tokens: limit.burst.into(),
+                refilled_at: now,
+                reservations: HashMap::new(),
+                pruned_at: now,
+            }),
+        }
+    }
+
+    /// Reserves a token for message `id`, unless it already holds one, and
+    /// says how long until it may use it. Zero once it may.
+    pub(crate) fn reserve(&self, id: Uuid) -> Duration {
+        let mut state = self.refilled();
+        let now = state.refilled_at;
+        if let Some(at) = state.reservations.get(&id) {
+            return at.saturating_duration_since(now);
+        }
+        if now.duration_since(state.pruned_at) >= RESERVATION_TTL {
+            state
+                .reservations
+                .retain(|_, at| now.duration_since(*at) < RESERVATION_TTL);
+            state.pruned_at = now;
+        }
+        state.tokens -= 1.0;
+        let wait = Duration::from_secs_f64((-state.tokens).max(0.0) / self.limit.per_second);
+        state.reservations.insert(id, now + wait);
+        wait
+    }
+
+    /// Hands the token reserved for message `id` over to it.
+    pub(crate) fn settle(&self, id: Uuid) {
+        self.state
+            .lock()
+            .expect("poisoned")
+            .reservations
+            .remove(&id);
+    }
+
+    fn refilled(&self) -> MutexGuard<'_, State> {
+        let mut state = self.state.lock().expect("poisoned");
+        let now = Instant::now();
+        let refill = now.duration_since(state.refilled_at).as_secs_f64() * self.limit.per_second;
+        state.tokens = (state.tokens + refill).min(self.limit.burst.into());
+        state.refilled_at = now;
+        state
+    }
+}
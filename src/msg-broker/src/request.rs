@@ -0,0 +1,77 @@
+use std::{collections::HashMap, sync::Arc, sync::Mutex};
+
+use tokio::sync::oneshot;
+use uuid::Uuid;
+
+use bincode::error::DecodeError;
+
+use crate::{CodecError, Decoders, HandlerError, Payload, PublishError};
+
+/// A handler that answers every message with a reply, see
+/// [`crate::Publisher::request`].
+pub trait RequestHandler: Send + Sync + 'static {
+    type Context;
+    type Error: From<DecodeError> + Into<HandlerError<Self::Error>>;
+    /// See [`crate::Handler::Msg`].
+    type Msg: Payload + Send + Sync;
+    type Reply: Payload + Send;
+
+    const ROUTING_KEY: &str;
+
+    /// See [`crate::Handler::SCHEMA_VERSION`].
+    const SCHEMA_VERSION: u32 = 1;
+
+    fn handle(
+        &self,
+        ctx: Arc<Self::Context>,
+        msg: Self::Msg,
+    ) -> impl Future<Output = Result<Self::Reply, Self::Error>> + Send;
+
+    /// Decoders for messages published with an older
+    /// [`RequestHandler::SCHEMA_VERSION`].
+    fn decoders() -> Decoders<Self::Msg> {
+        Decoders::new()
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum RequestError {
+    #[error(transparent)]
+    Publish(#[from] PublishError),
+    #[error(transparent)]
+    Codec(#[from] CodecError),
+    #[error("no reply within the request timeout")]
+    Timeout,
+    #[error("request failed: {0}")]
+    Handler(String),
+    #[error("broker stopped before replying")]
+    Closed,
+}
+
+type Reply = Result<Vec<u8>, String>;
+
+/// Requests waiting for a reply, by message id. Shared between a broker and
+/// its publishers, so replies never leave the process.
+#[derive(Default)]
+pub(crate) struct Replies {
+    pending: Mutex<HashMap<Uuid, oneshot::Sender<Reply>>>,
+}
+
+impl Replies {
+    pub(crate) fn register(&self, id: Uuid) -> oneshot::Receiver<Reply> {
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().expect("poisoned").insert(id, tx);
+        rx
+    }
+
+    pub(crate) fn cancel(&self, id: Uuid) {
+        self.pending.lock().expect("poisoned").remove(&id);
+    }
+
+    /// Answers request `id`, the first reply wins.
+    pub(crate) fn reply(&self, id: Uuid, reply: Reply) {
+        if let Some(tx) = self.pending.lock().expect("poisoned").remove(&id) {
+            let _ = tx.send(reply);
+        }
+    }
+}
@@ -0,0 +1,38 @@
+use std::sync::Arc;
+
+use tokio::sync::watch;
+
+/// Tells a broker, and anything else holding a clone, to shut down. See
+/// [`crate::MessageBrokerBuilder::shutdown`].
+#[derive(Clone)]
+pub struct ShutdownToken {
+    tx: Arc<watch::Sender<bool>>,
+}
+
+impl ShutdownToken {
+    pub fn new() -> Self {
+        ShutdownToken {
+            tx: Arc::new(watch::Sender::new(false)),
+        }
+    }
+
+    pub fn shutdown(&self) {
+        self.tx.send_replace(true);
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        *self.tx.borrow()
+    }
+
+    /// Waits until [`ShutdownToken::shutdown`] is called on any clone.
+    pub async fn wait(&self) {
+        let mut rx = self.tx.subscribe();
+        let _ = rx.wait_for(|shutdown| *shutdown).await;
+    }
+}
+
+impl Default for ShutdownToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
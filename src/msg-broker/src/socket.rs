@@ -0,0 +1,443 @@
+use std::{
+    convert::Infallible,
+    fmt, io,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use bincode::{Decode, Encode};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+    sync::Mutex,
+};
+
+use crate::{
+    Delivery, InMemoryTransport, Message, Receipt, RetryPolicy, ShutdownToken, Transport,
+    TransportError, handler_trait::BoxFuture,
+};
+
+/// Frames above this size are refused, so a corrupt length prefix cannot make
+/// the reader allocate gigabytes.
+const MAX_FRAME_LEN: u32 = 16 * 1024 * 1024;
+
+/// Where a [`SocketTransport`] listens, parsed from `unix:/path/to/socket` or
+/// `host:port`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Endpoint {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl FromStr for Endpoint {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.strip_prefix("unix:") {
+            Some(path) => Endpoint::Unix(path.into()),
+            None => Endpoint::Tcp(s.to_string()),
+        })
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Tcp(addr) => write!(f, "{}", addr),
+            Endpoint::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T> Stream for T where T: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl Endpoint {
+    async fn connect(&self) -> io::Result<Box<dyn Stream>> {
+        Ok(match self {
+            Endpoint::Tcp(addr) => Box::new(TcpStream::connect(addr).await?),
+            Endpoint::Unix(path) => Box::new(UnixStream::connect(path).await?),
+        })
+    }
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    async fn accept(&self) -> io::Result<Box<dyn Stream>> {
+        Ok(match self {
+            Listener::Tcp(listener) => Box::new(listener.accept().await?.0),
+            Listener::Unix(listener) => Box::new(listener.accept().await?.0),
+        })
+    }
+}
+
+/// One frame on the wire, sent after its length as a big-endian `u32`. A
+/// publish carries the whole [`Message`], routing key and envelope included,
+/// and is answered with an ack or a nack before the next one is sent.
+/// Messages carry a version, a publisher sending another one is hung up on
+/// instead of misread.
+#[derive(Debug, Encode, Decode)]
+enum Frame {
+    Publish { mode: Mode, message: Message },
+    Ack,
+    Nack(Nack),
+}
+
+/// Which [`Transport`] method the publisher called.
+#[derive(Debug, Clone, Copy, Encode, Decode)]
+enum Mode {
+    Send,
+    TrySend,
+    /// Delay in milliseconds.
+    SendAfter(u64),
+}
+
+#[derive(Debug, Encode, Decode)]
+enum Nack {
+    Closed,
+    Full,
+    Failed(String),
+}
+
+async fn write_frame<S>(stream: &mut S, frame: &Frame) -> Result<(), TransportError>
+where
+    S: AsyncWrite + Unpin + ?Sized,
+{
+    let data = bincode::encode_to_vec(frame, bincode::config::standard())?;
+    let len = u32::try_from(data.len())
+        .ok()
+        .filter(|len| *len <= MAX_FRAME_LEN)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "frame too large"))?;
+    stream.write_u32(len).await?;
+    stream.write_all(&data).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// `None` once the other end hung up between frames.
+async fn read_frame<S>(stream: &mut S) -> Result<Option<Frame>, TransportError>
+where
+    S: AsyncRead + Unpin + ?Sized,
+{
+    let len = match stream.read_u32().await {
+        Ok(len) => len,
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    if len > MAX_FRAME_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "frame too large").into());
+    }
+    let mut data = vec![0; len as usize];
+    stream.read_exact(&mut data).await?;
+    let (frame, _) = bincode::decode_from_slice(&data, bincode::config::standard())?;
+    Ok(Some(frame))
+}
+
+/// Takes messages from [`RemoteTransport`]s in other processes and queues
+/// them on an inner transport, which the broker consumes as usual. A message
+/// is acked to its publisher once the inner transport accepted it. Closing
+/// stops listening and hangs up on every publisher.
+pub struct SocketTransport {
+    inner: Arc<dyn Transport>,
+    endpoint: Endpoint,
+    closed: ShutdownToken,
+}
+
+impl SocketTransport {
+    /// Listens on `endpoint`, queueing on an [`InMemoryTransport`].
+    pub async fn bind(endpoint: Endpoint) -> Result<Self, TransportError> {
+        Self::bind_with(endpoint, Arc::new(InMemoryTransport::default())).await
+    }
+
+    /// Listens on `endpoint`, queueing on `inner`. A Unix socket's path must
+    /// not exist yet, it is removed again once the transport is closed or
+    /// dropped.
+    pub async fn bind_with(
+        endpoint: Endpoint,
+        inner: Arc<dyn Transport>,
+    ) -> Result<Self, TransportError> {
+        let (listener, endpoint) = match endpoint {
+            Endpoint::Tcp(addr) => {
+                let listener = TcpListener::bind(&addr).await?;
+                let addr = listener.local_addr()?.to_string();
+                (Listener::Tcp(listener), Endpoint::Tcp(addr))
+            }
+            Endpoint::Unix(path) => {
+                let listener = UnixListener::bind(&path)?;
+                (Listener::Unix(listener), Endpoint::Unix(path))
+            }
+        };
+        let closed = ShutdownToken::new();
+        tokio::spawn(accept(listener, inner.clone(), closed.clone()));
+        Ok(SocketTransport {
+            inner,
+            endpoint,
+            closed,
+        })
+    }
+
+    /// Where the transport listens, with the port filled in when bound to
+    /// port 0.
+    pub fn endpoint(&self) -> &Endpoint {
+        &self.endpoint
+    }
+
+    /// Hangs up on every publisher and frees a Unix socket's path, so it can
+    /// be bound again right away.
+    fn hang_up(&self) {
+        if self.closed.is_shutdown() {
+            return;
+        }
+        self.closed.shutdown();
+        if let Endpoint::Unix(path) = &self.endpoint {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+async fn accept(listener: Listener, inner: Arc<dyn Transport>, closed: ShutdownToken) {
+    loop {
+        let stream = tokio::select! {
+            stream = listener.accept() => stream,
+            _ = closed.wait() => break,
+        };
+        match stream {
+            Ok(stream) => {
+                tokio::spawn(serve(stream, inner.clone(), closed.clone()));
+            }
+            Err(err) => {
+                println!("WARN: failed to accept publisher: {}", err);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+    }
+}
+
+/// Answers the publishes of one connection until the publisher hangs up or
+/// the transport is closed.
+async fn serve(mut stream: Box<dyn Stream>, inner: Arc<dyn Transport>, closed: ShutdownToken) {
+    loop {
+        let frame = tokio::select! {
+            frame = read_frame(&mut stream) => frame,
+            _ = closed.wait() => return,
+        };
+        let (mode, message) = match frame {
+            Ok(Some(Frame::Publish { mode, message })) => (mode, message),
+            Ok(None) => return,
+            Ok(Some(frame)) => {
+                println!("WARN: unexpected frame from publisher: {:?}", frame);
+                return;
+            }
+            Err(err) => {
+                println!("WARN: failed to read from publisher: {}", err);
+                return;
+            }
+        };
+        let res = match mode {
+            Mode::Send => inner.send(message),
+            Mode::TrySend => inner.try_send(message),
+            Mode::SendAfter(millis) => inner.send_after(message, Duration::from_millis(millis)),
+        };
+        // A send waiting for room must not hold the connection open past a
+        // close.
+        let res = tokio::select! {
+            res = res => res,
+            _ = closed.wait() => return,
+        };
+        let reply = match res {
+            Ok(()) => Frame::Ack,
+            Err(TransportError::Closed) => Frame::Nack(Nack::Closed),
+            Err(TransportError::Full) => Frame::Nack(Nack::Full),
+            Err(err) => Frame::Nack(Nack::Failed(err.to_string())),
+        };
+        if let Err(err) = write_frame(&mut stream, &reply).await {
+            println!("WARN: failed to ack publisher: {}", err);
+            return;
+        }
+    }
+}
+
+impl Drop for SocketTransport {
+    fn drop(&mut self) {
+        self.hang_up();
+    }
+}
+
+impl Transport for SocketTransport {
+    fn send(&self, msg: Message) -> BoxFuture<'_, Result<(), TransportError>> {
+        self.inner.send(msg)
+    }
+
+    fn try_send(&self, msg: Message) -> BoxFuture<'_, Result<(), TransportError>> {
+        self.inner.try_send(msg)
+    }
+
+    fn send_after(
+        &self,
+        msg: Message,
+        delay: Duration,
+    ) -> BoxFuture<'_, Result<(), TransportError>> {
+        self.inner.send_after(msg, delay)
+    }
+
+    fn recv(&self) -> BoxFuture<'_, Result<Option<Delivery>, TransportError>> {
+        self.inner.recv()
+    }
+
+    fn ack(&self, receipt: Receipt) -> BoxFuture<'_, Result<(), TransportError>> {
+        self.inner.ack(receipt)
+    }
+
+    fn requeue(
+        &self,
+        receipt: Receipt,
+        msg: Message,
+        delay: Duration,
+    ) -> BoxFuture<'_, Result<(), TransportError>> {
+        self.inner.requeue(receipt, msg, delay)
+    }
+
+    fn close(&self) {
+        self.hang_up();
+        self.inner.close();
+    }
+
+    fn take_remaining(&self) -> BoxFuture<'_, Vec<Message>> {
+        self.inner.take_remaining()
+    }
+}
+
+/// Publishes to a [`SocketTransport`] in another process, hand it to
+/// [`crate::Publisher::new`]. Every message waits for the broker's ack. A
+/// broken connection is reopened and the message sent again, so a message
+/// whose ack got lost can arrive twice. Nothing is ever delivered through it.
+pub struct RemoteTransport {
+    endpoint: Endpoint,
+    reconnect: RetryPolicy,
+    conn: Mutex<Option<Box<dyn Stream>>>,
+    closed: AtomicBool,
+}
+
+impl RemoteTransport {
+    /// Connects on the first publish.
+    pub fn new(endpoint: Endpoint) -> Self {
+        RemoteTransport {
+            endpoint,
+            reconnect: RetryPolicy::new(5)
+                .base_delay(Duration::from_millis(100))
+                .max_delay(Duration::from_secs(5)),
+            conn: Mutex::new(None),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    /// How often and how patiently a publish reconnects before failing, 5
+    /// attempts starting 100ms apart by default.
+    pub fn reconnect(mut self, reconnect: RetryPolicy) -> Self {
+        self.reconnect = reconnect;
+        self
+    }
+
+    async fn publish(&self, mode: Mode, message: Message) -> Result<(), TransportError> {
+        if self.closed.load(Ordering::Acquire) {
+            return Err(TransportError::Closed);
+        }
+        let frame = Frame::Publish { mode, message };
+        let mut conn = self.conn.lock().await;
+        let mut attempt = 1;
+        loop {
+            let err = match self.exchange(&mut conn, &frame).await {
+                Ok(Frame::Ack) => return Ok(()),
+                Ok(Frame::Nack(Nack::Closed)) => return Err(TransportError::Closed),
+                Ok(Frame::Nack(Nack::Full)) => return Err(TransportError::Full),
+                Ok(Frame::Nack(Nack::Failed(error))) => return Err(TransportError::Remote(error)),
+                Ok(_) => io::Error::new(io::ErrorKind::InvalidData, "unexpected reply").into(),
+                Err(err) => err,
+            };
+            *conn = None;
+            if !self.reconnect.should_retry(attempt) {
+                return Err(err);
+            }
+            let backoff = self.reconnect.backoff(attempt);
+            println!(
+                "WARN: failed to publish to {} on attempt {}, reconnecting in {:?}: {}",
+                self.endpoint, attempt, backoff, err
+            );
+            tokio::time::sleep(backoff).await;
+            attempt += 1;
+        }
+    }
+
+    /// Sends `frame` and reads its reply. The connection is only put back
+    /// once the reply is in, so a publish cancelled in between hangs up
+    /// rather than leave its reply to be read as the next one's.
+    async fn exchange(
+        &self,
+        conn: &mut Option<Box<dyn Stream>>,
+        frame: &Frame,
+    ) -> Result<Frame, TransportError> {
+        let mut stream = match conn.take() {
+            Some(stream) => stream,
+            None => self.endpoint.connect().await?,
+        };
+        write_frame(&mut stream, frame).await?;
+        match read_frame(&mut stream).await? {
+            Some(reply) => {
+                *conn = Some(stream);
+                Ok(reply)
+            }
+            None => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+        }
+    }
+}
+
+impl Transport for RemoteTransport {
+    fn send(&self, msg: Message) -> BoxFuture<'_, Result<(), TransportError>> {
+        Box::pin(self.publish(Mode::Send, msg))
+    }
+
+    fn try_send(&self, msg: Message) -> BoxFuture<'_, Result<(), TransportError>> {
+        Box::pin(self.publish(Mode::TrySend, msg))
+    }
+
+    fn send_after(
+        &self,
+        msg: Message,
+        delay: Duration,
+    ) -> BoxFuture<'_, Result<(), TransportError>> {
+        let millis = delay.as_millis().try_into().unwrap_or(u64::MAX);
+        Box::pin(self.publish(Mode::SendAfter(millis), msg))
+    }
+
+    /// Never delivers anything.
+    fn recv(&self) -> BoxFuture<'_, Result<Option<Delivery>, TransportError>> {
+        Box::pin(async { Ok(None) })
+    }
+
+    fn ack(&self, _receipt: Receipt) -> BoxFuture<'_, Result<(), TransportError>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn requeue(
+        &self,
+        _receipt: Receipt,
+        _msg: Message,
+        _delay: Duration,
+    ) -> BoxFuture<'_, Result<(), TransportError>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+    }
+}
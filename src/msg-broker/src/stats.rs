@@ -0,0 +1,268 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+/// Upper bounds of the latency histogram buckets, in seconds.
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Name, help, type and value of every metric with one sample per routing
+/// key.
+type Sample = (
+    &'static str,
+    &'static str,
+    &'static str,
+    fn(&RouteStats) -> u64,
+);
+
+const SAMPLES: [Sample; 8] = [
+    ("published_total", "Messages published.", "counter", |s| {
+        s.published
+    }),
+    (
+        "handled_total",
+        "Handler calls that succeeded.",
+        "counter",
+        |s| s.handled,
+    ),
+    (
+        "failed_transient_total",
+        "Handler calls that failed with a transient error.",
+        "counter",
+        |s| s.failed_transient,
+    ),
+    (
+        "failed_fatal_total",
+        "Handler calls that failed with a fatal error.",
+        "counter",
+        |s| s.failed_fatal,
+    ),
+    (
+        "retried_total",
+        "Messages requeued for a retry.",
+        "counter",
+        |s| s.retried,
+    ),
+    (
+        "deduplicated_total",
+        "Handler calls skipped as duplicates.",
+        "counter",
+        |s| s.deduplicated,
+    ),
+    (
+        "expired_total",
+        "Messages discarded because their TTL ran out.",
+        "counter",
+        |s| s.expired,
+    ),
+    (
+        "queue_depth",
+        "Messages waiting to be dispatched.",
+        "gauge",
+        |s| s.queue_depth,
+    ),
+];
+
+#[derive(Default)]
+pub(crate) struct Counters {
+    pub(crate) panics: AtomicU64,
+    routes: Mutex<HashMap<String, RouteStats>>,
+}
+
+impl Counters {
+    pub(crate) fn route(&self, routing_key: &str, f: impl FnOnce(&mut RouteStats)) {
+        let mut routes = self.routes.lock().expect("poisoned");
+        match routes.get_mut(routing_key) {
+            Some(stats) => f(stats),
+            None => f(routes.entry(routing_key.to_string()).or_default()),
+        }
+    }
+
+    pub(crate) fn published(&self, routing_key: &str) {
+        self.route(routing_key, |stats| {
+            stats.published += 1;
+            stats.queue_depth += 1;
+        });
+    }
+
+    pub(crate) fn queued(&self, routing_key: &str) {
+        self.route(routing_key, |stats| stats.queue_depth += 1);
+    }
+
+    pub(crate) fn dequeued(&self, routing_key: &str) {
+        self.route(routing_key, |stats| {
+            stats.queue_depth = stats.queue_depth.saturating_sub(1)
+        });
+    }
+}
+
+/// Counts for one routing key. Handler calls are counted, so a message
+/// handed to two handlers is handled twice.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RouteStats {
+    /// Published through the broker's publishers.
+    pub published: u64,
+    /// Handler calls that succeeded.
+    pub handled: u64,
+    pub failed_transient: u64,
+    pub failed_fatal: u64,
+    /// Messages requeued to retry a transient failure.
+    pub retried: u64,
+    /// Handler calls skipped because the message was a duplicate.
+    pub deduplicated: u64,
+    /// Messages discarded because their TTL ran out before dispatch.
+    pub expired: u64,
+    /// Published or requeued and not yet dispatched. Messages published by
+    /// other processes are not counted.
+    pub queue_depth: u64,
+    /// How long handler calls took, batches count once.
+    pub latency: Histogram,
+}
+
+/// A latency histogram with fixed buckets from 5ms to 10s.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Histogram {
+    counts: [u64; BUCKETS.len()],
+    count: u64,
+    sum: Duration,
+}
+
+impl Histogram {
+    pub(crate) fn observe(&mut self, latency: Duration) {
+        let secs = latency.as_secs_f64();
+        if let Some(bucket) = BUCKETS.iter().position(|&bound| secs <= bound) {
+            self.counts[bucket] += 1;
+        }
+        self.count += 1;
+        self.sum += latency;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn sum(&self) -> Duration {
+        self.sum
+    }
+
+    /// Each bucket's upper bound in seconds with the number of observations
+    /// at or below it.
+    pub fn buckets(&self) -> Vec<(f64, u64)> {
+        let cumulative = self.counts.iter().scan(0, |total, count| {
+            *total += count;
+            Some(*total)
+        });
+        BUCKETS.into_iter().zip(cumulative).collect()
+    }
+}
+
+/// Counters of a running broker, see [`crate::MessageBroker::stats`].
+#[derive(Clone)]
+pub struct BrokerStats {
+    counters: Arc<Counters>,
+}
+
+impl BrokerStats {
+    pub(crate) fn new(counters: Arc<Counters>) -> Self {
+        BrokerStats { counters }
+    }
+
+    /// Handler calls that panicked.
+    pub fn panics(&self) -> u64 {
+        self.counters.panics.load(Ordering::Relaxed)
+    }
+
+    /// A snapshot of every routing key seen so far.
+    pub fn routes(&self) -> BTreeMap<String, RouteStats> {
+        let routes = self.counters.routes.lock().expect("poisoned");
+        routes
+            .iter()
+            .map(|(key, stats)| (key.clone(), stats.clone()))
+            .collect()
+    }
+
+    pub fn route(&self, routing_key: &str) -> RouteStats {
+        let routes = self.counters.routes.lock().expect("poisoned");
+        routes.get(routing_key).cloned().unwrap_or_default()
+    }
+
+    /// The stats in the Prometheus text exposition format.
+    pub fn prometheus(&self) -> String {
+        let routes = (self.routes().into_iter())
+            .map(|(key, stats)| (label(&key), stats))
+            .collect::<Vec<_>>();
+        let mut out = String::new();
+        for (name, help, kind, value) in SAMPLES {
+            metric(&mut out, name, help, kind);
+            for (key, stats) in &routes {
+                let _ = writeln!(
+                    out,
+                    "msg_broker_{name}{{routing_key=\"{key}\"}} {}",
+                    value(stats)
+                );
+            }
+        }
+
+        let name = "handler_latency_seconds";
+        metric(&mut out, name, "How long handler calls took.", "histogram");
+        for (key, stats) in &routes {
+            let latency = &stats.latency;
+            for (bound, count) in latency.buckets() {
+                let _ = writeln!(
+                    out,
+                    "msg_broker_{name}_bucket{{routing_key=\"{key}\",le=\"{bound}\"}} {count}"
+                );
+            }
+            let _ = writeln!(
+                out,
+                "msg_broker_{name}_bucket{{routing_key=\"{key}\",le=\"+Inf\"}} {}",
+                latency.count()
+            );
+            let _ = writeln!(
+                out,
+                "msg_broker_{name}_sum{{routing_key=\"{key}\"}} {}",
+                latency.sum().as_secs_f64()
+            );
+            let _ = writeln!(
+                out,
+                "msg_broker_{name}_count{{routing_key=\"{key}\"}} {}",
+                latency.count()
+            );
+        }
+
+        metric(
+            &mut out,
+            "panics_total",
+            "Handler calls that panicked.",
+            "counter",
+        );
+        let _ = writeln!(out, "msg_broker_panics_total {}", self.panics());
+        out
+    }
+}
+
+fn metric(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP msg_broker_{name} {help}");
+    let _ = writeln!(out, "# TYPE msg_broker_{name} {kind}");
+}
+
+/// Escapes a label value, routing keys come from publishers.
+fn label(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
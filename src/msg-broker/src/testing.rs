@@ -0,0 +1,373 @@
+//! Drives handlers through the real dispatch path one message at a time.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::{
+    Admin, Admission, BatchMessageHandler, Batchers, BrokerStats, Codec, CodecError, DeadLetters,
+    Delivery, HandlerError, Message, MessageBrokerBuilder, Payload, Publisher, Receipt, Route,
+    Shared, Transport, TransportError, handler_trait::BoxFuture,
+};
+
+/// A transport that keeps everything published to it. Delays are ignored,
+/// messages come out by [`crate::Priority`], then in the order they were sent
+/// or requeued.
+#[derive(Default)]
+pub struct TestTransport {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    published: Vec<Message>,
+    queue: VecDeque<Message>,
+    /// The id and attempt of every message put back, in order.
+    requeued: Vec<(Uuid, u32)>,
+    closed: bool,
+}
+
+impl TestTransport {
+    /// Every message sent so far, requeued messages excluded.
+    pub fn published(&self) -> Vec<Message> {
+        self.state.lock().expect("poisoned").published.clone()
+    }
+
+    /// Messages waiting to be delivered.
+    pub fn pending(&self) -> usize {
+        self.state.lock().expect("poisoned").queue.len()
+    }
+
+    fn requeued(&self) -> usize {
+        self.state.lock().expect("poisoned").requeued.len()
+    }
+
+    /// The attempt `id` was put back with, if it was put back after the
+    /// first `since` requeues.
+    fn requeued_as(&self, id: Uuid, since: usize) -> Option<u32> {
+        let state = self.state.lock().expect("poisoned");
+        (state.requeued[since..].iter())
+            .find(|(requeued, _)| *requeued == id)
+            .map(|(_, attempt)| *attempt)
+    }
+
+    /// Takes up to `limit` queued messages for `routing_key`, oldest first.
+    fn take(&self, routing_key: &str, limit: usize) -> Vec<Delivery> {
+        let mut state = self.state.lock().expect("poisoned");
+        let mut taken = Vec::new();
+        let mut index = 0;
+        while taken.len() < limit && index < state.queue.len() {
+            match state.queue[index].routing_key == routing_key {
+                true => taken.extend(state.queue.remove(index)),
+                false => index += 1,
+            }
+        }
+        taken
+            .into_iter()
+            .map(|message| Delivery {
+                message,
+                receipt: Receipt::new(0),
+            })
+            .collect()
+    }
+
+    fn push(&self, msg: Message) -> Result<(), TransportError> {
+        let mut state = self.state.lock().expect("poisoned");
+        if state.closed {
+            return Err(TransportError::Closed);
+        }
+        state.published.push(msg.clone());
+        state.queue.push_back(msg);
+        Ok(())
+    }
+}
+
+impl Transport for TestTransport {
+    fn send(&self, msg: Message) -> BoxFuture<'_, Result<(), TransportError>> {
+        let res = self.push(msg);
+        Box::pin(async { res })
+    }
+
+    fn try_send(&self, msg: Message) -> BoxFuture<'_, Result<(), TransportError>> {
+        self.send(msg)
+    }
+
+    fn send_after(
+        &self,
+        msg: Message,
+        _delay: Duration,
+    ) -> BoxFuture<'_, Result<(), TransportError>> {
+        self.send(msg)
+    }
+
+    /// Never waits, `None` once the queue is empty.
+    fn recv(&self) -> BoxFuture<'_, Result<Option<Delivery>, TransportError>> {
+        let mut state = self.state.lock().expect("poisoned");
+        let next = (state.queue.iter().enumerate())
+            .min_by_key(|(_, msg)| msg.envelope().priority())
+            .map(|(index, _)| index);
+        let msg = next.and_then(|index| state.queue.remove(index));
+        drop(state);
+        let delivery = msg.map(|message| Delivery {
+            message,
+            receipt: Receipt::new(0),
+        });
+        Box::pin(async { Ok(delivery) })
+    }
+
+    fn ack(&self, _receipt: Receipt) -> BoxFuture<'_, Result<(), TransportError>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn requeue(
+        &self,
+        _receipt: Receipt,
+        msg: Message,
+        _delay: Duration,
+    ) -> BoxFuture<'_, Result<(), TransportError>> {
+        let mut state = self.state.lock().expect("poisoned");
+        (state.requeued).push((msg.envelope.id(), msg.envelope.attempt()));
+        state.queue.push_back(msg);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn close(&self) {
+        self.state.lock().expect("poisoned").closed = true;
+    }
+}
+
+/// One message taken through dispatch by [`TestBroker::step`].
+pub struct Step<Err> {
+    /// The message as it was delivered.
+    pub message: Message,
+    /// What each handler returned, by handler name. Empty when the message
+    /// was parked or unrouted, or its batch handler returned nothing for it.
+    pub results: Vec<(&'static str, Result<(), HandlerError<Err>>)>,
+    /// Whether the message went back on the queue for another attempt.
+    pub retried: bool,
+    /// Whether the message went back on the queue without using up an
+    /// attempt, because it could not be dispatched yet.
+    pub parked: bool,
+}
+
+impl<Err> Step<Err> {
+    /// The delivery attempt, starting at 1.
+    pub fn attempt(&self) -> u32 {
+        self.message.envelope().attempt()
+    }
+
+    /// Whether every handler succeeded.
+    pub fn is_ok(&self) -> bool {
+        self.results.iter().all(|(_, res)| res.is_ok())
+    }
+}
+
+impl<Err> fmt::Debug for Step<Err>
+where
+    Err: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Step")
+            .field("message", &self.message)
+            .field("results", &self.results)
+            .field("retried", &self.retried)
+            .field("parked", &self.parked)
+            .finish()
+    }
+}
+
+/// A broker that only dispatches when told to. A message for a batch handler
+/// is handled together with the messages queued behind it for the same
+/// routing key, up to the handler's batch size, without lingering.
+pub struct TestBroker<Ctx, Err>
+where
+    Ctx: Send + Sync + 'static,
+    Err: Send + Sync + 'static,
+{
+    shared: Arc<Shared<Ctx, Err>>,
+    transport: Arc<TestTransport>,
+    /// Steps of a batch not yet returned by [`TestBroker::step`].
+    batched: Mutex<VecDeque<Step<Err>>>,
+}
+
+impl<Ctx, Err> TestBroker<Ctx, Err>
+where
+    Ctx: Sync + Send + 'static,
+    Err: fmt::Debug + Sync + Send + 'static,
+{
+    /// Builds `builder` on a [`TestTransport`], replacing its transport.
+    pub fn new(mut builder: MessageBrokerBuilder<Ctx, Err>) -> Self {
+        let transport = Arc::new(TestTransport::default());
+        builder.transport = transport.clone();
+        TestBroker {
+            shared: builder.build().shared,
+            transport,
+            batched: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn publisher(&self) -> Publisher {
+        Publisher::new(self.transport.clone())
+            .with_replies(self.shared.replies.clone())
+            .with_counters(self.shared.counters.clone())
+    }
+
+    /// Every message published so far, including those handlers published.
+    pub fn published(&self) -> Vec<Message> {
+        self.transport.published()
+    }
+
+    /// Messages waiting to be dispatched, retries included.
+    pub fn pending(&self) -> usize {
+        self.transport.pending()
+    }
+
+    /// Dispatches the next queued message, `None` if there is none. A whole
+    /// batch is dispatched at once, its steps are returned one at a time.
+    pub async fn step(&self) -> Option<Step<Err>> {
+        if let Some(step) = self.batched.lock().expect("poisoned").pop_front() {
+            return Some(step);
+        }
+        let delivery = self.transport.recv().await.ok()??;
+        let batch_handler = (self.shared.batch_handlers.iter())
+            .find(|handler| handler.routing_key == delivery.message.routing_key);
+        let Some(handler) = batch_handler else {
+            return self.dispatch(delivery, &Batchers::new()).await;
+        };
+        let mut steps = self.dispatch_batch(handler, delivery).await;
+        let step = steps.pop_front();
+        self.batched.lock().expect("poisoned").extend(steps);
+        step
+    }
+
+    /// Steps until the queue is empty, or until every message left has been
+    /// parked in a row, as when their routing key is paused or its breaker
+    /// is open.
+    pub async fn run_until_idle(&self) -> Vec<Step<Err>> {
+        let mut steps = Vec::new();
+        let mut parked = 0;
+        while let Some(step) = self.step().await {
+            parked = match step.parked {
+                true => parked + 1,
+                false => 0,
+            };
+            steps.push(step);
+            if parked > 0 && parked >= self.pending() {
+                break;
+            }
+        }
+        steps
+    }
+
+    /// Dispatches `delivery`, `None` if it went to one of `batchers`.
+    async fn dispatch(&self, delivery: Delivery, batchers: &Batchers) -> Option<Step<Err>> {
+        let message = delivery.message.clone();
+        let since = self.transport.requeued();
+        let results = (self.shared)
+            .dispatch(delivery, batchers, Admission::Park)
+            .await?;
+        Some(self.step_of(message, results, since))
+    }
+
+    /// Dispatches `first` and the messages queued behind it for the same
+    /// batch handler, then handles those that were batched together.
+    async fn dispatch_batch(
+        &self,
+        handler: &BatchMessageHandler<Ctx, Err>,
+        first: Delivery,
+    ) -> VecDeque<Step<Err>> {
+        let (tx, mut rx) = mpsc::channel(handler.max_batch_size);
+        let batchers = Batchers::from([(handler.routing_key, tx)]);
+        let since = self.transport.requeued();
+        let queued = (self.transport).take(handler.routing_key, handler.max_batch_size - 1);
+        let mut steps = VecDeque::new();
+        let mut messages = Vec::new();
+        for delivery in std::iter::once(first).chain(queued) {
+            let message = delivery.message.clone();
+            match self.dispatch(delivery, &batchers).await {
+                Some(step) => steps.push_back(step),
+                None => messages.push(message),
+            }
+        }
+        let mut batch = Vec::with_capacity(messages.len());
+        while let Ok(delivery) = rx.try_recv() {
+            batch.push(delivery);
+        }
+        let results = self.shared.dispatch_batch(handler, batch).await;
+        for (message, res) in messages.into_iter().zip(results) {
+            let results = res.map(|res| (handler.name, res)).into_iter().collect();
+            steps.push_back(self.step_of(message, results, since));
+        }
+        steps
+    }
+
+    /// The step of `message`, which was requeued, if at all, after the first
+    /// `since` requeues.
+    fn step_of(
+        &self,
+        message: Message,
+        results: Vec<(&'static str, Result<(), HandlerError<Err>>)>,
+        since: usize,
+    ) -> Step<Err> {
+        let requeued = self.transport.requeued_as(message.envelope().id(), since);
+        let attempt = message.envelope().attempt();
+        Step {
+            retried: requeued.is_some_and(|requeued| requeued > attempt),
+            parked: requeued == Some(attempt),
+            message,
+            results,
+        }
+    }
+
+    pub fn dead_letters(&self) -> DeadLetters {
+        DeadLetters::new(self.shared.dead_letters.clone(), self.publisher())
+    }
+
+    pub fn stats(&self) -> BrokerStats {
+        BrokerStats::new(self.shared.counters.clone())
+    }
+
+    pub fn admin(&self) -> Admin {
+        Admin::new(self.shared.breakers.clone())
+    }
+}
+
+/// A [`Publisher`] that keeps what is published instead of delivering it, for
+/// handlers that publish downstream.
+#[derive(Default)]
+pub struct FakePublisher {
+    transport: Arc<TestTransport>,
+}
+
+impl FakePublisher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Publishes into this fake, hand it to the handler's context.
+    pub fn publisher(&self) -> Publisher {
+        Publisher::new(self.transport.clone())
+    }
+
+    pub fn published(&self) -> Vec<Message> {
+        self.transport.published()
+    }
+
+    /// Decodes every message published to route `T`.
+    pub fn decode<T>(&self) -> Result<Vec<T::Msg>, CodecError>
+    where
+        T: Route,
+    {
+        self.published()
+            .iter()
+            .filter(|msg| msg.routing_key() == T::ROUTING_KEY)
+            .map(|msg| <T::Msg as Payload>::Codec::decode(msg.data()))
+            .collect()
+    }
+}
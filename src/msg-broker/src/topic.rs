@@ -0,0 +1,46 @@
+/// A routing key pattern. Keys are split on `.`, `*` matches exactly one
+/// segment and `#` matches any number of segments, so `swap.*` matches
+/// `swap.raydium` and `swap.#` also matches `swap` and `swap.raydium.clmm`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Pattern {
+    segments: Vec<String>,
+}
+
+impl Pattern {
+    pub(crate) fn new(pattern: &str) -> Self {
+        Pattern {
+            segments: pattern.split('.').map(str::to_string).collect(),
+        }
+    }
+
+    pub(crate) fn matches(&self, routing_key: &str) -> bool {
+        let key = routing_key.split('.').collect::<Vec<_>>();
+        matches(&self.segments, &key)
+    }
+}
+
+fn matches(pattern: &[String], key: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => key.is_empty(),
+        Some((segment, rest)) if segment == "#" => {
+            (0..=key.len()).any(|skip| matches(rest, &key[skip..]))
+        }
+        Some((segment, rest)) => match key.split_first() {
+            Some((first, key)) => (segment == "*" || segment == first) && matches(rest, key),
+            None => false,
+        },
+    }
+}
+
+/// What the broker does with a message no handler is subscribed to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Unrouted {
+    /// Keep it in the dead letter store so it can be replayed once a handler
+    /// exists.
+    #[default]
+    DeadLetter,
+    /// Ack it and move on.
+    Drop,
+    /// Stop the broker.
+    Close,
+}
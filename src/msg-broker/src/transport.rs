@@ -0,0 +1,387 @@
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use bincode::error::{DecodeError, EncodeError};
+use db::{DbClient, DbConfig, error::DbError};
+use tokio::{
+    sync::{
+        Mutex, Notify,
+        mpsc::{self, Receiver, Sender, error::TrySendError},
+    },
+    time::Instant,
+};
+use uuid::Uuid;
+
+use crate::{Message, Priority, delay::DelayQueue, handler_trait::BoxFuture, wire};
+
+#[derive(thiserror::Error, Debug)]
+pub enum TransportError {
+    #[error("transport closed")]
+    Closed,
+    #[error("transport full")]
+    Full,
+    #[error(transparent)]
+    Db(#[from] DbError),
+    #[error(transparent)]
+    Encode(#[from] EncodeError),
+    #[error(transparent)]
+    Decode(#[from] DecodeError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("remote transport failed: {0}")]
+    Remote(String),
+}
+
+/// Identifies a delivery to the transport it came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Receipt(i64);
+
+impl Receipt {
+    pub const fn new(id: i64) -> Self {
+        Receipt(id)
+    }
+
+    pub const fn id(&self) -> i64 {
+        self.0
+    }
+}
+
+/// A message taken off a transport. It stays owned by the broker until it is
+/// acked or requeued.
+#[derive(Debug)]
+pub struct Delivery {
+    pub message: Message,
+    pub receipt: Receipt,
+}
+
+/// The queue between [`crate::Publisher`]s and a [`crate::MessageBroker`].
+pub trait Transport: Send + Sync + 'static {
+    /// Enqueues `msg`, waiting for capacity if the transport is bounded.
+    fn send(&self, msg: Message) -> BoxFuture<'_, Result<(), TransportError>>;
+
+    /// Enqueues `msg` without waiting for capacity, failing with
+    /// [`TransportError::Full`] instead.
+    fn try_send(&self, msg: Message) -> BoxFuture<'_, Result<(), TransportError>>;
+
+    /// Enqueues `msg` to be delivered once `delay` has passed.
+    fn send_after(
+        &self,
+        msg: Message,
+        delay: Duration,
+    ) -> BoxFuture<'_, Result<(), TransportError>>;
+
+    /// Waits for the next delivery, `None` once the transport is closed and
+    /// drained.
+    fn recv(&self) -> BoxFuture<'_, Result<Option<Delivery>, TransportError>>;
+
+    /// The delivery was handled and must not be delivered again.
+    fn ack(&self, receipt: Receipt) -> BoxFuture<'_, Result<(), TransportError>>;
+
+    /// Replaces the delivery with `msg`, delivered again after `delay`.
+    fn requeue(
+        &self,
+        receipt: Receipt,
+        msg: Message,
+        delay: Duration,
+    ) -> BoxFuture<'_, Result<(), TransportError>>;
+
+    /// Stops accepting new messages.
+    fn close(&self);
+
+    /// Takes every message a closed transport still holds, so the broker can
+    /// dead letter them once its drain times out. Durable transports keep
+    /// theirs for the next run and return none.
+    fn take_remaining(&self) -> BoxFuture<'_, Vec<Message>> {
+        Box::pin(async { Vec::new() })
+    }
+}
+
+/// A bounded channel per [`Priority`], anything still queued is lost when the
+/// process exits. Higher priority lanes are always drained first. Delayed
+/// messages wait outside the channels. Once closed, no new messages are
+/// taken, but queued ones, retries and parked messages keep being delivered
+/// until none are left.
+pub struct InMemoryTransport {
+    tx: [Sender<Message>; 3],
+    rx: Mutex<[Receiver<Message>; 3]>,
+    delayed: [DelayQueue<Message>; 3],
+    closed: AtomicBool,
+    close: Notify,
+}
+
+impl InMemoryTransport {
+    /// Every priority gets its own `capacity`.
+    pub fn new(capacity: usize) -> Self {
+        let [(high_tx, high_rx), (normal_tx, normal_rx), (low_tx, low_rx)] =
+            Priority::ALL.map(|_| mpsc::channel(capacity));
+        InMemoryTransport {
+            tx: [high_tx, normal_tx, low_tx],
+            rx: Mutex::new([high_rx, normal_rx, low_rx]),
+            delayed: Priority::ALL.map(|_| DelayQueue::new()),
+            closed: AtomicBool::new(false),
+            close: Notify::new(),
+        }
+    }
+}
+
+impl Default for InMemoryTransport {
+    fn default() -> Self {
+        Self::new(12)
+    }
+}
+
+impl Transport for InMemoryTransport {
+    fn send(&self, msg: Message) -> BoxFuture<'_, Result<(), TransportError>> {
+        let tx = &self.tx[msg.envelope.priority().lane()];
+        Box::pin(async move {
+            if self.closed.load(Ordering::Acquire) {
+                return Err(TransportError::Closed);
+            }
+            tx.send(msg).await.map_err(|_| TransportError::Closed)
+        })
+    }
+
+    fn try_send(&self, msg: Message) -> BoxFuture<'_, Result<(), TransportError>> {
+        let tx = &self.tx[msg.envelope.priority().lane()];
+        if self.closed.load(Ordering::Acquire) {
+            return Box::pin(async { Err(TransportError::Closed) });
+        }
+        let res = tx.try_send(msg).map_err(|err| match err {
+            TrySendError::Full(_) => TransportError::Full,
+            TrySendError::Closed(_) => TransportError::Closed,
+        });
+        Box::pin(async { res })
+    }
+
+    fn send_after(
+        &self,
+        msg: Message,
+        delay: Duration,
+    ) -> BoxFuture<'_, Result<(), TransportError>> {
+        let lane = msg.envelope.priority().lane();
+        let res = match self.closed.load(Ordering::Acquire) {
+            true => Err(TransportError::Closed),
+            false => {
+                self.delayed[lane].push(msg, Instant::now() + delay);
+                Ok(())
+            }
+        };
+        Box::pin(async { res })
+    }
+
+    fn recv(&self) -> BoxFuture<'_, Result<Option<Delivery>, TransportError>> {
+        Box::pin(async move {
+            let mut rx = self.rx.lock().await;
+            let msg = 'recv: loop {
+                if self.closed.load(Ordering::Acquire) {
+                    rx.iter_mut().for_each(Receiver::close);
+                }
+                let mut drained = true;
+                for (rx, delayed) in rx.iter_mut().zip(&self.delayed) {
+                    if let Some(msg) = delayed.pop_due() {
+                        break 'recv Some(msg);
+                    }
+                    match rx.try_recv() {
+                        Ok(msg) => break 'recv Some(msg),
+                        // Closing a receiver does not disconnect it.
+                        Err(_) if rx.is_closed() && delayed.is_empty() => {}
+                        Err(_) => drained = false,
+                    }
+                }
+                if drained {
+                    break None;
+                }
+                let [high, normal, low] = &mut *rx;
+                let [high_delayed, normal_delayed, low_delayed] = &self.delayed;
+                tokio::select! {
+                    biased;
+                    Some(msg) = high.recv() => break Some(msg),
+                    Some(msg) = normal.recv() => break Some(msg),
+                    Some(msg) = low.recv() => break Some(msg),
+                    _ = high_delayed.wait() => {}
+                    _ = normal_delayed.wait() => {}
+                    _ = low_delayed.wait() => {}
+                    _ = self.close.notified() => {}
+                }
+            };
+            Ok(msg.map(|message| Delivery {
+                message,
+                receipt: Receipt::new(0),
+            }))
+        })
+    }
+
+    fn ack(&self, _receipt: Receipt) -> BoxFuture<'_, Result<(), TransportError>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn requeue(
+        &self,
+        _receipt: Receipt,
+        msg: Message,
+        delay: Duration,
+    ) -> BoxFuture<'_, Result<(), TransportError>> {
+        let lane = msg.envelope.priority().lane();
+        self.delayed[lane].push(msg, Instant::now() + delay);
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Refuses new messages right away. Only one receive runs at a time, so
+    /// waking it is enough.
+    fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.close.notify_one();
+    }
+
+    fn take_remaining(&self) -> BoxFuture<'_, Vec<Message>> {
+        Box::pin(async move {
+            let mut rx = self.rx.lock().await;
+            let mut remaining = Vec::new();
+            for (rx, delayed) in rx.iter_mut().zip(&self.delayed) {
+                rx.close();
+                while let Ok(msg) = rx.try_recv() {
+                    remaining.push(msg);
+                }
+                remaining.extend(delayed.take_all());
+            }
+            remaining
+        })
+    }
+}
+
+/// A durable queue on the `broker_jobs` table. Claimed jobs are leased rather
+/// than deleted, so a message in flight when the process dies is delivered
+/// again once its lease runs out. Jobs are claimed by [`Priority`], then in
+/// the order they were enqueued. Once closed, jobs that are already due are
+/// still handed out until none are left. A job whose message cannot be
+/// decoded is moved to `dead_letters` as is, so it never blocks the queue.
+pub struct PostgresTransport {
+    db_client: DbClient,
+    poll_interval: Duration,
+    lease: Duration,
+    closed: AtomicBool,
+    close: Notify,
+}
+
+impl PostgresTransport {
+    pub fn new(db_client: DbClient) -> Self {
+        PostgresTransport {
+            db_client,
+            poll_interval: Duration::from_millis(500),
+            lease: Duration::from_secs(60),
+            closed: AtomicBool::new(false),
+            close: Notify::new(),
+        }
+    }
+
+    pub async fn connect(db_config: &DbConfig) -> Result<Self, TransportError> {
+        Ok(Self::new(DbClient::connect(db_config).await?))
+    }
+
+    /// How long to wait before polling again once the queue is empty.
+    pub fn poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// How long a claimed job is hidden from other workers. Handlers running
+    /// longer than this may see the same message twice.
+    pub fn lease(mut self, lease: Duration) -> Self {
+        self.lease = lease;
+        self
+    }
+}
+
+impl Transport for PostgresTransport {
+    fn send(&self, msg: Message) -> BoxFuture<'_, Result<(), TransportError>> {
+        self.send_after(msg, Duration::ZERO)
+    }
+
+    /// The jobs table is unbounded, so this is the same as `send`.
+    fn try_send(&self, msg: Message) -> BoxFuture<'_, Result<(), TransportError>> {
+        self.send(msg)
+    }
+
+    fn send_after(
+        &self,
+        msg: Message,
+        delay: Duration,
+    ) -> BoxFuture<'_, Result<(), TransportError>> {
+        Box::pin(async move {
+            if self.closed.load(Ordering::Acquire) {
+                return Err(TransportError::Closed);
+            }
+            let data = wire::encode(&msg)?;
+            let priority = msg.envelope.priority().lane() as i16;
+            self.db_client
+                .enqueue_broker_job(&msg.routing_key, &data, priority, delay)
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn recv(&self) -> BoxFuture<'_, Result<Option<Delivery>, TransportError>> {
+        Box::pin(async move {
+            loop {
+                let closed = self.closed.load(Ordering::Acquire);
+                if let Some(job) = self.db_client.claim_broker_job(self.lease).await? {
+                    match wire::decode(&job.message) {
+                        Ok(message) => {
+                            return Ok(Some(Delivery {
+                                message,
+                                receipt: Receipt::new(job.job_id),
+                            }));
+                        }
+                        Err(err) => {
+                            println!(
+                                "WARN: failed to decode broker job {} for {}, dead lettering it: {}",
+                                job.job_id, job.routing_key, err
+                            );
+                            let error = format!("undecodable message: {}", err);
+                            self.db_client
+                                .dead_letter_broker_job(job.job_id, Uuid::now_v7(), &error)
+                                .await?;
+                            continue;
+                        }
+                    }
+                }
+                if closed {
+                    return Ok(None);
+                }
+                tokio::select! {
+                    _ = tokio::time::sleep(self.poll_interval) => {}
+                    _ = self.close.notified() => {}
+                }
+            }
+        })
+    }
+
+    fn ack(&self, receipt: Receipt) -> BoxFuture<'_, Result<(), TransportError>> {
+        Box::pin(async move {
+            self.db_client.delete_broker_job(receipt.id()).await?;
+            Ok(())
+        })
+    }
+
+    fn requeue(
+        &self,
+        receipt: Receipt,
+        msg: Message,
+        delay: Duration,
+    ) -> BoxFuture<'_, Result<(), TransportError>> {
+        Box::pin(async move {
+            let data = wire::encode(&msg)?;
+            self.db_client
+                .reschedule_broker_job(receipt.id(), &data, delay)
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.close.notify_waiters();
+    }
+}
@@ -0,0 +1,76 @@
+use bincode::{
+    BorrowDecode, Decode, Encode,
+    de::{BorrowDecoder, Decoder},
+    enc::Encoder,
+    error::{DecodeError, EncodeError},
+};
+
+use crate::{Envelope, Message};
+
+/// Starts every encoded [`Message`], so stray bytes are rejected before the
+/// version is read.
+const MARKER: u8 = 0xFF;
+
+/// Layout of [`Message`] and [`Envelope`] after the marker. Bump it whenever
+/// either changes shape, and keep decoding the versions that may still be
+/// queued.
+const VERSION: u8 = 1;
+
+impl Encode for Message {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        MARKER.encode(encoder)?;
+        VERSION.encode(encoder)?;
+        self.routing_key.encode(encoder)?;
+        self.data.encode(encoder)?;
+        self.envelope.encode(encoder)
+    }
+}
+
+impl<Context> Decode<Context> for Message {
+    fn decode<D: Decoder<Context = Context>>(decoder: &mut D) -> Result<Self, DecodeError> {
+        let marker = u8::decode(decoder)?;
+        if marker != MARKER {
+            return Err(DecodeError::OtherString(format!(
+                "not a message, starts with {:#04x}",
+                marker
+            )));
+        }
+        let version = u8::decode(decoder)?;
+        if version != VERSION {
+            return Err(DecodeError::OtherString(format!(
+                "unsupported message version {}",
+                version
+            )));
+        }
+        Ok(Message {
+            routing_key: String::decode(decoder)?,
+            data: Vec::decode(decoder)?,
+            envelope: Envelope::decode(decoder)?,
+        })
+    }
+}
+
+impl<'de, Context> BorrowDecode<'de, Context> for Message {
+    fn borrow_decode<D: BorrowDecoder<'de, Context = Context>>(
+        decoder: &mut D,
+    ) -> Result<Self, DecodeError> {
+        Decode::decode(decoder)
+    }
+}
+
+/// Encodes a message for the `broker_jobs` table.
+pub(crate) fn encode(msg: &Message) -> Result<Vec<u8>, EncodeError> {
+    bincode::encode_to_vec(msg, bincode::config::standard())
+}
+
+/// Decodes a message from the `broker_jobs` table.
+pub(crate) fn decode(data: &[u8]) -> Result<Message, DecodeError> {
+    let (msg, read) = bincode::decode_from_slice(data, bincode::config::standard())?;
+    match read == data.len() {
+        true => Ok(msg),
+        false => Err(DecodeError::OtherString(format!(
+            "{} trailing bytes",
+            data.len() - read
+        ))),
+    }
+}
@@ -0,0 +1,65 @@
+use std::{sync::Arc, time::Duration};
+
+use db::{DbClient, DbConfig};
+use msg_broker::{
+    DeadLetterStore, PostgresDeadLetterStore, PostgresTransport, Publisher, Route, Transport,
+};
+
+/// Env var with the `DbConfig` of a scratch database, as JSON. The broker
+/// tables are created in it if missing. These tests are ignored unless run
+/// with `--ignored`, and fail without it.
+const TEST_DB: &str = "MSG_BROKER_TEST_DB";
+
+fn db_config() -> DbConfig {
+    let config = std::env::var(TEST_DB).unwrap_or_else(|_| panic!("{} is not set", TEST_DB));
+    serde_json::from_str(&config).expect("invalid test db config")
+}
+
+struct Numbers;
+
+impl Route for Numbers {
+    type Msg = u32;
+
+    const ROUTING_KEY: &str = "numbers";
+}
+
+#[tokio::test]
+#[ignore = "needs a scratch Postgres database in MSG_BROKER_TEST_DB"]
+async fn test_undecodable_jobs_are_dead_lettered() {
+    let db_config = db_config();
+    let db_client = DbClient::connect(&db_config).await.unwrap();
+    db_client.migrate().await.unwrap();
+    db_client
+        .enqueue_broker_job("corrupt", b"corrupt", 0, Duration::ZERO)
+        .await
+        .unwrap();
+    let transport = PostgresTransport::connect(&db_config)
+        .await
+        .unwrap()
+        .poll_interval(Duration::from_millis(10));
+    let transport = Arc::new(transport);
+    Publisher::new(transport.clone())
+        .send::<Numbers>(7)
+        .await
+        .unwrap();
+
+    let delivery = tokio::time::timeout(Duration::from_secs(5), transport.recv())
+        .await
+        .expect("queue is blocked")
+        .unwrap()
+        .unwrap();
+    assert_eq!(delivery.message.routing_key(), Numbers::ROUTING_KEY);
+    transport.ack(delivery.receipt).await.unwrap();
+
+    let store = PostgresDeadLetterStore::new(db_client);
+    let dead_letter = store
+        .list()
+        .await
+        .unwrap()
+        .into_iter()
+        .find(|dead_letter| dead_letter.routing_key == "corrupt")
+        .expect("corrupt job was not dead lettered");
+    assert_eq!(dead_letter.data, b"corrupt");
+    assert_eq!(dead_letter.schema_version, 0);
+    store.remove(dead_letter.id).await.unwrap();
+}
@@ -0,0 +1,170 @@
+use std::{sync::Arc, time::Duration};
+
+use bincode::error::DecodeError;
+use msg_broker::{
+    Endpoint, Handler, HandlerError, InMemoryTransport, MessageBroker, MessageHandler,
+    PublishError, Publisher, RemoteTransport, RetryPolicy, ShutdownToken, SocketTransport,
+    Transport,
+};
+use tokio::sync::mpsc;
+
+#[derive(Debug)]
+struct TestError;
+
+impl From<DecodeError> for TestError {
+    fn from(_: DecodeError) -> Self {
+        TestError
+    }
+}
+
+impl From<TestError> for HandlerError<TestError> {
+    fn from(value: TestError) -> Self {
+        HandlerError::fatal(value)
+    }
+}
+
+/// Reports every message it sees.
+struct Collect;
+
+impl Handler for Collect {
+    type Context = mpsc::UnboundedSender<u32>;
+    type Error = TestError;
+    type Msg = u32;
+
+    const ROUTING_KEY: &str = "collect";
+
+    async fn handle(&self, ctx: Arc<Self::Context>, msg: u32) -> Result<(), TestError> {
+        ctx.send(msg).map_err(|_| TestError)
+    }
+}
+
+/// Runs a broker on `transport` until the returned token is shut down.
+fn run_broker(transport: SocketTransport) -> (ShutdownToken, mpsc::UnboundedReceiver<u32>) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let shutdown = ShutdownToken::new();
+    let broker = MessageBroker::builder(tx)
+        .handler(MessageHandler::new(Collect))
+        .transport(transport)
+        .shutdown(shutdown.clone())
+        .drain_timeout(Duration::from_secs(1))
+        .build();
+    tokio::spawn(broker.run());
+    (shutdown, rx)
+}
+
+async fn recv(rx: &mut mpsc::UnboundedReceiver<u32>) -> u32 {
+    tokio::time::timeout(Duration::from_secs(5), rx.recv())
+        .await
+        .expect("message not handled in time")
+        .expect("broker stopped")
+}
+
+fn socket_path(name: &str) -> Endpoint {
+    let path =
+        std::env::temp_dir().join(format!("msg-broker-{}-{}.sock", name, std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    Endpoint::Unix(path)
+}
+
+#[tokio::test]
+async fn test_publishes_over_tcp() {
+    let transport = SocketTransport::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let endpoint = transport.endpoint().clone();
+    let (shutdown, mut rx) = run_broker(transport);
+
+    let publisher = Publisher::new(Arc::new(RemoteTransport::new(endpoint)));
+    for msg in 1..=3 {
+        publisher.send::<Collect>(msg).await.unwrap();
+    }
+    for msg in 1..=3 {
+        assert_eq!(recv(&mut rx).await, msg);
+    }
+    shutdown.shutdown();
+}
+
+#[tokio::test]
+async fn test_publishes_over_unix_socket() {
+    let endpoint = socket_path("unix");
+    let transport = SocketTransport::bind(endpoint.clone()).await.unwrap();
+    let (shutdown, mut rx) = run_broker(transport);
+
+    let publisher = Publisher::new(Arc::new(RemoteTransport::new(endpoint)));
+    publisher
+        .send_after::<Collect>(Duration::from_millis(10), 7)
+        .await
+        .unwrap();
+    assert_eq!(recv(&mut rx).await, 7);
+    shutdown.shutdown();
+}
+
+#[tokio::test]
+async fn test_reconnects_after_broker_restarts() {
+    let endpoint = socket_path("restart");
+    let transport = SocketTransport::bind(endpoint.clone()).await.unwrap();
+    let (shutdown, mut rx) = run_broker(transport);
+
+    let reconnect = RetryPolicy::new(10).base_delay(Duration::from_millis(20));
+    let remote = RemoteTransport::new(endpoint.clone()).reconnect(reconnect);
+    let publisher = Publisher::new(Arc::new(remote));
+    publisher.send::<Collect>(1).await.unwrap();
+    assert_eq!(recv(&mut rx).await, 1);
+
+    shutdown.shutdown();
+    while rx.recv().await.is_some() {}
+    let transport = SocketTransport::bind(endpoint).await.unwrap();
+    let (shutdown, mut rx) = run_broker(transport);
+    publisher.send::<Collect>(2).await.unwrap();
+    assert_eq!(recv(&mut rx).await, 2);
+    shutdown.shutdown();
+}
+
+#[tokio::test]
+async fn test_gives_up_without_broker() {
+    let reconnect = RetryPolicy::new(2).base_delay(Duration::from_millis(1));
+    let remote = RemoteTransport::new(socket_path("missing")).reconnect(reconnect);
+    let publisher = Publisher::new(Arc::new(remote));
+    let res = publisher.send::<Collect>(1).await;
+    assert!(matches!(res, Err(PublishError::Transport(_))));
+}
+
+/// A socket transport whose queue takes a single message.
+async fn bind_full(name: &str) -> (SocketTransport, Publisher) {
+    let endpoint = socket_path(name);
+    let inner = Arc::new(InMemoryTransport::new(1));
+    let transport = SocketTransport::bind_with(endpoint.clone(), inner)
+        .await
+        .unwrap();
+    let remote = RemoteTransport::new(endpoint).reconnect(RetryPolicy::none());
+    let publisher = Publisher::new(Arc::new(remote));
+    publisher.send::<Collect>(1).await.unwrap();
+    (transport, publisher)
+}
+
+#[tokio::test]
+async fn test_cancelled_publish_does_not_leave_its_ack_behind() {
+    let (transport, publisher) = bind_full("cancel").await;
+    let res = tokio::time::timeout(Duration::from_millis(50), publisher.send::<Collect>(2)).await;
+    assert!(res.is_err(), "publish into a full queue did not wait");
+
+    // Makes room, the cancelled publish goes through and is acked.
+    assert!(transport.recv().await.unwrap().is_some());
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let res = publisher.try_send::<Collect>(3).await;
+    assert!(matches!(res, Err(PublishError::Full)));
+}
+
+#[tokio::test]
+async fn test_close_hangs_up_on_waiting_publishers() {
+    let (transport, publisher) = bind_full("close").await;
+    let waiting = tokio::spawn(async move { publisher.send::<Collect>(2).await });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    transport.close();
+    let res = tokio::time::timeout(Duration::from_secs(1), waiting)
+        .await
+        .expect("publisher still waiting after close")
+        .unwrap();
+    assert!(res.is_err());
+}